@@ -0,0 +1,51 @@
+CREATE TABLE rules_revision (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    revision INTEGER NOT NULL
+);
+
+INSERT INTO rules_revision (id, revision) VALUES (1, 0);
+
+CREATE TRIGGER rules_insert_revision AFTER INSERT ON rules
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER rules_update_revision AFTER UPDATE ON rules
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER rules_delete_revision AFTER DELETE ON rules
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER patterns_insert_revision AFTER INSERT ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER patterns_update_revision AFTER UPDATE ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER patterns_delete_revision AFTER DELETE ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_insert_revision AFTER INSERT ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_update_revision AFTER UPDATE ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_delete_revision AFTER DELETE ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
@@ -0,0 +1,64 @@
+CREATE TABLE patterns_new (
+    id INTEGER PRIMARY KEY,
+    pattern TEXT NOT NULL,
+    rule_id INTEGER NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+    updated_by TEXT NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+INSERT INTO patterns_new (id, pattern, rule_id, updated_by, updated_at)
+SELECT id, pattern, rule_id, updated_by, updated_at FROM patterns
+WHERE rule_id IN (SELECT id FROM rules);
+
+DROP TABLE patterns;
+ALTER TABLE patterns_new RENAME TO patterns;
+
+CREATE INDEX pattern_rule_fk_idx on patterns(rule_id);
+
+CREATE TABLE responses_new (
+    id INTEGER PRIMARY KEY,
+    response TEXT NOT NULL,
+    rule_id INTEGER NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+    updated_by TEXT NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+INSERT INTO responses_new (id, response, rule_id, updated_by, updated_at)
+SELECT id, response, rule_id, updated_by, updated_at FROM responses
+WHERE rule_id IN (SELECT id FROM rules);
+
+DROP TABLE responses;
+ALTER TABLE responses_new RENAME TO responses;
+
+CREATE INDEX response_rule_fk_idx on responses(rule_id);
+
+-- Dropping the old tables dropped their revision triggers too
+CREATE TRIGGER patterns_insert_revision AFTER INSERT ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER patterns_update_revision AFTER UPDATE ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER patterns_delete_revision AFTER DELETE ON patterns
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_insert_revision AFTER INSERT ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_update_revision AFTER UPDATE ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER responses_delete_revision AFTER DELETE ON responses
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
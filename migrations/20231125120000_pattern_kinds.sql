@@ -0,0 +1 @@
+ALTER TABLE patterns ADD COLUMN kind TEXT NOT NULL DEFAULT 'substring';
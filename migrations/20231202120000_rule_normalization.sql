@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN normalize INTEGER NOT NULL DEFAULT 0;
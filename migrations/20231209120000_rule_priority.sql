@@ -0,0 +1,17 @@
+ALTER TABLE rules ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN stop_processing INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE guild_settings (
+    guild_id INTEGER PRIMARY KEY,
+    match_policy TEXT NOT NULL DEFAULT 'first-match'
+);
+
+CREATE TRIGGER guild_settings_insert_revision AFTER INSERT ON guild_settings
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER guild_settings_update_revision AFTER UPDATE ON guild_settings
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
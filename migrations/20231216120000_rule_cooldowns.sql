@@ -0,0 +1,3 @@
+ALTER TABLE rules ADD COLUMN global_cooldown INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN channel_cooldown INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN user_cooldown INTEGER NOT NULL DEFAULT 0;
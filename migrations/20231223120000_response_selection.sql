@@ -0,0 +1,11 @@
+ALTER TABLE responses ADD COLUMN weight INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE rules ADD COLUMN shuffle INTEGER NOT NULL DEFAULT 0;
+
+-- Responses a rule has left to give in a channel before it starts over, as `id:left` pairs
+-- counting the draws left of each response
+CREATE TABLE shuffle_bags (
+    rule_id INTEGER NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+    channel_id INTEGER NOT NULL,
+    response_ids TEXT NOT NULL,
+    PRIMARY KEY (rule_id, channel_id)
+);
@@ -0,0 +1,11 @@
+ALTER TABLE responses ADD COLUMN kind TEXT NOT NULL DEFAULT 'message';
+ALTER TABLE responses ADD COLUMN embed_title TEXT NOT NULL DEFAULT '';
+ALTER TABLE responses ADD COLUMN embed_image TEXT NOT NULL DEFAULT '';
+
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    data BLOB NOT NULL,
+    updated_by TEXT NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
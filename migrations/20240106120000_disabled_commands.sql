@@ -0,0 +1,5 @@
+CREATE TABLE disabled_commands (
+    guild_id INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    PRIMARY KEY (guild_id, command)
+);
@@ -0,0 +1,33 @@
+-- The guild a rule answers in, rules without one answer in every guild
+CREATE TABLE rule_guilds (
+    rule_id INTEGER PRIMARY KEY REFERENCES rules(id) ON DELETE CASCADE,
+    guild_id INTEGER NOT NULL
+);
+
+-- Channels a rule is restricted to when allowed, or kept out of when not
+CREATE TABLE rule_channels (
+    rule_id INTEGER NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+    channel_id INTEGER NOT NULL,
+    allowed INTEGER NOT NULL,
+    PRIMARY KEY (rule_id, channel_id)
+);
+
+CREATE TRIGGER rule_guilds_insert_revision AFTER INSERT ON rule_guilds
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER rule_guilds_delete_revision AFTER DELETE ON rule_guilds
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER rule_channels_insert_revision AFTER INSERT ON rule_channels
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER rule_channels_delete_revision AFTER DELETE ON rule_channels
+BEGIN
+    UPDATE rules_revision SET revision = revision + 1;
+END;
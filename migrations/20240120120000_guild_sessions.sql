@@ -0,0 +1,6 @@
+-- Tokens and sessions are for editing the rules of the guild `!edit` was sent in.
+-- Those from before have no guild, so their users need a new link.
+DELETE FROM tokens;
+DELETE FROM sessions;
+ALTER TABLE tokens ADD COLUMN guild_id INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN guild_id INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,22 @@
+-- What members of a guild may do in the web UI: 'viewer', 'editor' or 'admin'
+CREATE TABLE access_users (
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY (guild_id, user_id)
+);
+
+-- The same for everyone with a Discord role
+CREATE TABLE access_roles (
+    guild_id INTEGER NOT NULL,
+    role_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY (guild_id, role_id)
+);
+
+-- Tokens and sessions from before were issued to anyone, so they only get to look
+ALTER TABLE tokens ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer';
+ALTER TABLE sessions ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer';
+
+-- Locked rules can only be changed by admins
+ALTER TABLE rules ADD COLUMN locked INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,25 @@
+-- Every change to a rule, with JSON snapshots of the rule before and after it
+CREATE TABLE rule_changes (
+    id INTEGER PRIMARY KEY,
+    -- Not a foreign key, changes outlive the rules they are about
+    rule_id INTEGER NOT NULL,
+    guild_id INTEGER,
+    kind TEXT NOT NULL,
+    before TEXT,
+    after TEXT,
+    -- The Discord user id of whoever made the change, or 'system' for changes the bot made itself
+    changed_by TEXT NOT NULL,
+    changed_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+CREATE INDEX rule_changes_rule_idx ON rule_changes(rule_id);
+
+CREATE TRIGGER rule_changes_no_update BEFORE UPDATE ON rule_changes
+BEGIN
+    SELECT RAISE(ABORT, 'rule_changes is append-only');
+END;
+
+CREATE TRIGGER rule_changes_no_delete BEFORE DELETE ON rule_changes
+BEGIN
+    SELECT RAISE(ABORT, 'rule_changes is append-only');
+END;
@@ -0,0 +1,17 @@
+-- Files belong to the guild they were uploaded in. Those from before have no guild
+-- until the bot adopts them for DISCORD_GUILD_ID, like unscoped rules.
+CREATE TABLE files_new (
+    id INTEGER PRIMARY KEY,
+    guild_id INTEGER,
+    name TEXT NOT NULL,
+    data BLOB NOT NULL,
+    updated_by TEXT NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    UNIQUE (guild_id, name)
+);
+
+INSERT INTO files_new (id, guild_id, name, data, updated_by, updated_at)
+SELECT id, NULL, name, data, updated_by, updated_at FROM files;
+
+DROP TABLE files;
+ALTER TABLE files_new RENAME TO files;
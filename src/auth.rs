@@ -1,26 +1,137 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use dashmap::DashMap;
-use lazy_static::lazy_static;
+use rocket::http::{Cookie, Status};
+use rocket::request::{FromRequest, Outcome, Request};
 use uuid::Uuid;
 
-lazy_static! {
-    pub static ref TOKENS: DashMap<String, (u64, Instant)> = DashMap::new();
-}
+use crate::db::{Db, DbError, Role};
+use crate::discord::public_url;
+
+const TOKEN_TTL: Duration = Duration::from_secs(900);
+const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SESSION_COOKIE: &str = "session";
 
-pub fn generate_token(user_id: u64) -> String {
+/// Issues a token for `user_id` to work on the rules of `guild_id` with `role`.
+pub async fn generate_token(
+    db: &Db,
+    user_id: u64,
+    guild_id: u64,
+    role: Role,
+) -> Result<String, DbError> {
     let token = Uuid::new_v4().to_string();
-    TOKENS.insert(token.clone(), (user_id, Instant::now()));
-    token
+    db.create_token(&token, &user_id.to_string(), guild_id, role)
+        .await?;
+    Ok(token)
+}
+
+/// The cookie holding a session, kept from scripts and sent only over HTTPS when the
+/// editor is served that way.
+fn session_cookie(session: String) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, session))
+        .http_only(true)
+        .secure(public_url().scheme() == "https")
+        .build()
+}
+
+/// Exchanges a single-use token for a new session, returning the session and its editor.
+async fn exchange_token(db: &Db, token: &str) -> Result<Option<(String, Editor)>, DbError> {
+    let Some((user, guild_id, role)) = db.take_token(token, TOKEN_TTL.as_secs() as i64).await?
+    else {
+        return Ok(None);
+    };
+    let session = Uuid::new_v4().to_string();
+    db.create_session(&session, &user, guild_id, role).await?;
+    let editor = user.parse().ok().map(|user_id| Editor {
+        user_id,
+        guild_id,
+        role,
+    });
+    Ok(editor.map(|editor| (session, editor)))
+}
+
+async fn validate_session(db: &Db, session: &str) -> Result<Option<Editor>, DbError> {
+    let user = db
+        .get_session_user(session, SESSION_TTL.as_secs() as i64)
+        .await?;
+    Ok(user.and_then(|(user, guild_id, role)| {
+        let user_id = user.parse().ok()?;
+        Some(Editor {
+            user_id,
+            guild_id,
+            role,
+        })
+    }))
 }
 
-#[allow(dead_code)]
-pub fn validate_token(token: &str) -> bool {
-    match TOKENS.get(token) {
-        Some(entry) => {
-            let delta = Instant::now() - entry.value().1;
-            delta < Duration::from_secs(900)
+/// Periodically deletes expired tokens and sessions.
+pub fn sweep_expired_tokens(db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let deleted = db
+                .delete_expired_tokens(TOKEN_TTL.as_secs() as i64, SESSION_TTL.as_secs() as i64)
+                .await;
+            if let Err(why) = deleted {
+                println!("Error deleting expired tokens: {:?}", why);
+            }
+        }
+    });
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    /// The session or token couldn't be looked up.
+    Database,
+}
+
+fn database_error(why: DbError) -> Outcome<Editor, AuthError> {
+    println!("Error checking an editor: {:?}", why);
+    Outcome::Error((Status::InternalServerError, AuthError::Database))
+}
+
+/// A Discord user who opened the web UI with a valid `!edit` link.
+///
+/// The token from the link is exchanged for a session cookie on the first visit,
+/// so the htmx requests that follow don't need to carry it. Opening a new link replaces
+/// the session.
+/// Editors only see the rules of the guild they sent `!edit` in,
+/// and only change them if their role allows it.
+pub struct Editor {
+    pub user_id: u64,
+    pub guild_id: u64,
+    pub role: Role,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Editor {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let db = request.rocket().state::<Db>().expect("Db is managed");
+        let cookies = request.cookies();
+
+        // A fresh link wins over the session, as it may be for another guild or role
+        match request.query_value::<&str>("token") {
+            Some(Ok(token)) => match exchange_token(db, token).await {
+                Ok(Some((session, editor))) => {
+                    cookies.add(session_cookie(session));
+                    Outcome::Success(editor)
+                }
+                Ok(None) => Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
+                Err(why) => database_error(why),
+            },
+            _ => match cookies.get(SESSION_COOKIE) {
+                Some(cookie) => match validate_session(db, cookie.value()).await {
+                    Ok(Some(editor)) => Outcome::Success(editor),
+                    Ok(None) => Outcome::Error((Status::Unauthorized, AuthError::Invalid)),
+                    Err(why) => database_error(why),
+                },
+                None => Outcome::Error((Status::Unauthorized, AuthError::Missing)),
+            },
         }
-        None => false,
     }
 }
@@ -0,0 +1,413 @@
+use std::env;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serenity::model::prelude::{Message, Permissions, RoleId};
+use serenity::prelude::Context;
+use serenity::utils::{parse_role, parse_username};
+
+use crate::db::{Db, DbError, MatchPolicy, Role};
+use crate::discord::{edit_link, send_message, summarize};
+use crate::{auth, message};
+
+/// What a command needs to run.
+pub struct Invocation<'a> {
+    pub db: &'a Db,
+    pub ctx: &'a Context,
+    pub msg: &'a Message,
+    /// The arguments after the command name, see `parse_args`.
+    pub args: Vec<String>,
+}
+
+impl Invocation<'_> {
+    async fn say(&self, message: &str) {
+        send_message(self.msg.channel_id, self.ctx, message).await
+    }
+}
+
+/// A command run by sending a message starting with the prefix followed by its name.
+pub struct PrefixCommand {
+    pub name: &'static str,
+    /// Names of the arguments the command requires, shown in its usage.
+    pub args: &'static [&'static str],
+    pub description: &'static str,
+    /// What the author needs to be allowed to run it in a server.
+    pub permissions: Permissions,
+    /// Whether servers can turn it off with `disable`.
+    pub can_disable: bool,
+    pub run: for<'a> fn(Invocation<'a>) -> BoxFuture<'a, Result<()>>,
+}
+
+impl PrefixCommand {
+    fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{prefix}{}", self.name);
+        for arg in self.args {
+            usage.push_str(&format!(" <{arg}>"));
+        }
+        usage
+    }
+}
+
+/// Every prefix command, in the order `help` lists them.
+pub const COMMANDS: &[PrefixCommand] = &[
+    PrefixCommand {
+        name: "help",
+        args: &[],
+        description: "List the commands you can use here",
+        permissions: Permissions::empty(),
+        can_disable: false,
+        run: |invocation| Box::pin(help(invocation)),
+    },
+    PrefixCommand {
+        name: "edit",
+        args: &[],
+        description: "Get a link to the web UI for editing the rules of this server by DM",
+        permissions: Permissions::empty(),
+        can_disable: true,
+        run: |invocation| Box::pin(edit(invocation)),
+    },
+    PrefixCommand {
+        name: "policy",
+        args: &["first-match|all-matches|longest-pattern"],
+        description: "Choose which rules answer a message that matches several of them",
+        permissions: Permissions::MANAGE_GUILD,
+        can_disable: false,
+        run: |invocation| Box::pin(policy(invocation)),
+    },
+    PrefixCommand {
+        name: "summarize",
+        args: &[],
+        description: "Sum up what the channel is talking about",
+        permissions: Permissions::empty(),
+        can_disable: true,
+        run: |invocation| Box::pin(summarize_channel(invocation)),
+    },
+    PrefixCommand {
+        name: "enable",
+        args: &["command"],
+        description: "Turn a command back on in this server",
+        permissions: Permissions::MANAGE_GUILD,
+        can_disable: false,
+        run: |invocation| Box::pin(set_enabled(invocation, true)),
+    },
+    PrefixCommand {
+        name: "disable",
+        args: &["command"],
+        description: "Turn a command off in this server",
+        permissions: Permissions::MANAGE_GUILD,
+        can_disable: false,
+        run: |invocation| Box::pin(set_enabled(invocation, false)),
+    },
+    PrefixCommand {
+        name: "access",
+        args: &["viewer|editor|admin|none", "@user or @role"],
+        description: "Choose who may look at or change the rules in the web UI",
+        permissions: Permissions::MANAGE_GUILD,
+        can_disable: false,
+        run: |invocation| Box::pin(access(invocation)),
+    },
+];
+
+/// The prefix commands start with, `COMMAND_PREFIX` or `!` by default.
+pub fn prefix() -> String {
+    env::var("COMMAND_PREFIX").unwrap_or_else(|_| "!".to_string())
+}
+
+fn find_command(name: &str) -> Option<&'static PrefixCommand> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Names of the commands turned off where `msg` was sent.
+async fn disabled_commands(db: &Db, msg: &Message) -> Result<Vec<String>, DbError> {
+    match msg.guild_id {
+        Some(guild_id) => db.get_disabled_commands(guild_id.0).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Splits arguments on whitespace, keeping text in double quotes together.
+pub fn parse_args(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_quotes = false;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                quoted = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !arg.is_empty() || quoted {
+                    args.push(std::mem::take(&mut arg));
+                }
+                quoted = false;
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() || quoted {
+        args.push(arg);
+    }
+    args
+}
+
+/// Guild-wide permissions of the author of a guild message, from their roles.
+async fn author_permissions(ctx: &Context, msg: &Message) -> Option<Permissions> {
+    let guild_id = msg.guild_id?;
+    let guild = match guild_id.to_partial_guild(&ctx.http).await {
+        Ok(guild) => guild,
+        Err(why) => {
+            println!("Error fetching guild: {:?}", why);
+            return None;
+        }
+    };
+    if guild.owner_id == msg.author.id {
+        return Some(Permissions::all());
+    }
+
+    let everyone = RoleId(guild_id.0);
+    let permissions = msg
+        .member
+        .iter()
+        .flat_map(|member| member.roles.iter())
+        .chain([&everyone])
+        .filter_map(|id| guild.roles.get(id))
+        .fold(Permissions::empty(), |permissions, role| {
+            permissions | role.permissions
+        });
+    if permissions.administrator() {
+        return Some(Permissions::all());
+    }
+    Some(permissions)
+}
+
+/// Runs the command `msg` invokes, if any. Returns whether it was a command,
+/// in which case rules shouldn't answer it.
+pub async fn dispatch(db: &Db, ctx: &Context, msg: &Message) -> bool {
+    let prefix = prefix();
+    let Some(text) = msg.content.strip_prefix(&prefix) else {
+        return false;
+    };
+    let mut args = parse_args(text);
+    let Some(command) = args.first().and_then(|name| find_command(name)) else {
+        return false;
+    };
+    args.remove(0);
+    let invocation = Invocation { db, ctx, msg, args };
+
+    if msg.guild_id.is_some() {
+        let disabled = match disabled_commands(db, msg).await {
+            Ok(disabled) => disabled,
+            Err(why) => {
+                println!("Error fetching disabled commands: {:?}", why);
+                Vec::new()
+            }
+        };
+        if command.can_disable && disabled.iter().any(|name| name == command.name) {
+            return true;
+        }
+        if !command.permissions.is_empty() {
+            let allowed = author_permissions(ctx, msg)
+                .await
+                .is_some_and(|p| p.contains(command.permissions));
+            if !allowed {
+                let message = format!("You need the {} permission to do that", command.permissions);
+                invocation.say(&message).await;
+                return true;
+            }
+        }
+    } else if !command.permissions.is_empty() {
+        invocation.say("That command only works in a server").await;
+        return true;
+    }
+
+    if invocation.args.len() < command.args.len() {
+        let message = format!("Usage: {}", command.usage(&prefix));
+        invocation.say(&message).await;
+        return true;
+    }
+    if let Err(why) = (command.run)(invocation).await {
+        println!("Error running {}: {:?}", command.name, why);
+        let message = "Something went wrong, try again later";
+        send_message(msg.channel_id, ctx, message).await;
+    }
+    true
+}
+
+async fn help(invocation: Invocation<'_>) -> Result<()> {
+    let prefix = prefix();
+    let disabled = disabled_commands(invocation.db, invocation.msg).await?;
+    // Outside a server there are no permissions, and the commands needing some don't work
+    let permissions = author_permissions(invocation.ctx, invocation.msg)
+        .await
+        .unwrap_or_else(Permissions::empty);
+    let mut help = String::from("Commands:\n");
+    for command in COMMANDS {
+        if disabled.iter().any(|name| name == command.name)
+            || !permissions.contains(command.permissions)
+        {
+            continue;
+        }
+        help.push_str(&format!(
+            "`{}` {}\n",
+            command.usage(&prefix),
+            command.description
+        ));
+    }
+    invocation.say(&help).await;
+    Ok(())
+}
+
+async fn edit(invocation: Invocation<'_>) -> Result<()> {
+    let Some(guild_id) = invocation.msg.guild_id else {
+        invocation
+            .say("Send that in the server whose rules you want to edit")
+            .await;
+        return Ok(());
+    };
+    let Invocation { db, ctx, msg, .. } = invocation;
+    let role = if author_permissions(ctx, msg)
+        .await
+        .is_some_and(|p| p.manage_guild())
+    {
+        Some(Role::Admin)
+    } else {
+        let role_ids: Vec<u64> = msg
+            .member
+            .iter()
+            .flat_map(|member| member.roles.iter().map(|id| id.0))
+            .collect();
+        db.get_access(guild_id.0)
+            .await?
+            .role_of(msg.author.id.0, &role_ids)
+    };
+    let Some(role) = role else {
+        send_message(
+            msg.channel_id,
+            ctx,
+            "You haven't been given access to the rules of this server",
+        )
+        .await;
+        return Ok(());
+    };
+    let token = auth::generate_token(db, msg.author.id.0, guild_id.0, role).await?;
+    let link = edit_link(&token);
+    match msg.author.create_dm_channel(ctx).await {
+        Ok(dm) => send_message(dm.id, ctx, link.as_str()).await,
+        Err(why) => {
+            println!("Error creating DM channel: {:?}", why);
+            send_message(
+                msg.channel_id,
+                ctx,
+                "I couldn't send you a DM, please check your privacy settings",
+            )
+            .await
+        }
+    }
+    Ok(())
+}
+
+async fn policy(invocation: Invocation<'_>) -> Result<()> {
+    let Some(guild_id) = invocation.msg.guild_id else {
+        return Ok(());
+    };
+    let reply = match invocation.args[0].parse::<MatchPolicy>() {
+        Ok(policy) => {
+            invocation.db.set_match_policy(guild_id.0, policy).await?;
+            message::reload_rules(invocation.db).await?;
+            format!("Match policy set to {policy}")
+        }
+        Err(why) => why,
+    };
+    invocation.say(&reply).await;
+    Ok(())
+}
+
+async fn summarize_channel(invocation: Invocation<'_>) -> Result<()> {
+    let Invocation { ctx, msg, .. } = invocation;
+    if let Ok(message) = summarize(msg.channel_id, msg.id, ctx).await {
+        send_message(msg.channel_id, ctx, &message).await
+    }
+    Ok(())
+}
+
+async fn set_enabled(invocation: Invocation<'_>, enabled: bool) -> Result<()> {
+    let Some(guild_id) = invocation.msg.guild_id else {
+        return Ok(());
+    };
+    let name = &invocation.args[0];
+    let reply = match find_command(name) {
+        Some(command) if command.can_disable => {
+            invocation
+                .db
+                .set_command_enabled(guild_id.0, command.name, enabled)
+                .await?;
+            let state = if enabled { "enabled" } else { "disabled" };
+            format!("{} is now {state}", command.name)
+        }
+        Some(command) => format!("{} can't be turned off", command.name),
+        None => format!("There is no command called {name}"),
+    };
+    invocation.say(&reply).await;
+    Ok(())
+}
+
+async fn access(invocation: Invocation<'_>) -> Result<()> {
+    let Some(guild_id) = invocation.msg.guild_id else {
+        return Ok(());
+    };
+    let db = invocation.db;
+    let (role, target) = (&invocation.args[0], &invocation.args[1]);
+    let role = match role.as_str() {
+        "none" => None,
+        role => match role.parse::<Role>() {
+            Ok(role) => Some(role),
+            Err(why) => {
+                invocation.say(&why).await;
+                return Ok(());
+            }
+        },
+    };
+    let given = role.map_or("no access".to_string(), |role| format!("the {role} role"));
+
+    let reply = if let Some(user_id) = parse_username(target) {
+        db.set_user_access(guild_id.0, user_id, role).await?;
+        format!("<@{user_id}> now has {given}")
+    } else if let Some(role_id) = parse_role(target) {
+        db.set_role_access(guild_id.0, role_id, role).await?;
+        format!(
+            "Everyone with <@&{role_id}> now has {given}. Everyone editing the rules of this \
+            server needs a new link from `{}edit`",
+            prefix()
+        )
+    } else {
+        format!("{target} is neither a user nor a role, mention one")
+    };
+    invocation.say(&reply).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_are_split_on_whitespace_except_in_quotes() {
+        assert_eq!(
+            parse_args("policy  all-matches "),
+            ["policy", "all-matches"]
+        );
+        assert_eq!(
+            parse_args(r#"rule "kpop time" "" x"#),
+            ["rule", "kpop time", "", "x"]
+        );
+        assert!(parse_args("   ").is_empty());
+    }
+
+    #[test]
+    fn usage_lists_the_arguments() {
+        let command = find_command("disable").unwrap();
+        assert_eq!(command.usage("!"), "!disable <command>");
+    }
+}
@@ -1,41 +1,490 @@
 use hypersynthetic::prelude::*;
+use serenity::model::Timestamp;
 use std::fmt::Display;
 
-use crate::db::Rule;
+use crate::db::{
+    File, NewResponse, PatternKind, Response, ResponseKind, Role, Rule, RuleChange, RuleSettings,
+};
+use crate::diff::{diff_lines, DiffLine};
+use crate::validation::{MAX_PRIORITY, MAX_WEIGHT};
 
+/// Items with a button to delete each of them, shown only if `can_delete`.
 #[component]
-pub fn TableWihtSingleColumn<I, T>(items: I) -> HtmlFragment
+pub fn DeletableItems<I, T>(items: I, url: &str, confirm: &str, can_delete: bool) -> HtmlFragment
 where
-    I: IntoIterator<Item = T>,
+    I: IntoIterator<Item = (i64, T)>,
     T: Display,
 {
     html! {
         <table>
-            <tr :for={item in items}>
+            <tr :for={(id, item) in items}>
                 <td>{ item }</td>
+                <td>
+                    <DeleteButton url={ &format!("{url}/{id}") } confirm={ confirm } shown={ can_delete } />
+                </td>
             </tr>
         </table>
     }
 }
 
 #[component]
-pub fn RuleRow(rule: &Rule) -> HtmlFragment {
+fn DeleteButton(url: &str, confirm: &str, shown: bool) -> HtmlFragment {
+    if shown {
+        html! {
+            <button hx-delete={ url } hx-target="closest tr" hx-swap="delete"
+            hx-confirm={ confirm }>"❌"</button>
+        }
+    } else {
+        html! {}
+    }
+}
+
+/// Buttons to edit and delete a rule if `role` allows it, and to lock it for admins.
+#[component]
+fn RuleActions(rule: &Rule, role: Role) -> HtmlFragment {
+    if !role.can_edit(rule) {
+        return LockButton(rule, role);
+    }
+    html! {
+        <button hx-get="/modify-rule-form" hx-target="closest tbody" hx-swap="outerHTML"
+        hx-include="#modify-rule-{rule.id}">"✏️"</button>
+        <button hx-delete="/rules/{rule.id}" hx-target="closest tbody" hx-swap="delete"
+        hx-confirm="Delete rule {rule.name}?">"🗑️"</button>
+        <input id="modify-rule-{rule.id}" name="rule_id" type="hidden" value={ rule.id } />
+        <LockButton rule={ rule } role={ role } />
+    }
+}
+
+/// Whether a rule is locked, as a button to toggle it for admins.
+#[component]
+fn LockButton(rule: &Rule, role: Role) -> HtmlFragment {
+    match (role, rule.locked) {
+        (Role::Admin, true) => html! {
+            <button hx-put="/rules/{rule.id}/lock?locked=false" hx-target="closest tr" hx-swap="outerHTML"
+            title="Let editors change it">"🔒"</button>
+        },
+        (Role::Admin, false) => html! {
+            <button hx-put="/rules/{rule.id}/lock?locked=true" hx-target="closest tr" hx-swap="outerHTML"
+            title="Only let admins change it">"🔓"</button>
+        },
+        (_, true) => html! { <span title="Only admins can change it">"🔒"</span> },
+        (_, false) => html! {},
+    }
+}
+
+/// A rule as seen by someone with `role`.
+#[component]
+pub fn RuleRow(rule: &Rule, role: Role) -> HtmlFragment {
+    let can_edit = role.can_edit(rule);
     html! {
         <tr id="rule{rule.id}">
             <td>
                 <div style=" display: flex;">
                 { rule.name }
-                <button hx-get="/modify-rule-form" hx-target="closest tbody" hx-swap="outerHTML"
-                hx-include="#modify-rule-{rule.id}">"✏️"</button>
-                <input id="modify-rule-{rule.id}" name="rule_id" type="hidden" value={ rule.id } />
+                <a href="/rules/{rule.id}/history" title="History">"🕘"</a>
+                <RuleActions rule={ rule } role={ role } />
+            </div>
+            <small :for={setting in describe_settings(&rule.settings)}>{ setting }<br /></small>
+            </td>
+            <td>
+                <DeletableItems
+                    items={ rule.patterns.iter().map(|p| (p.id, format!("{} ({})", p.pattern, p.kind))) }
+                    url={ &format!("/rules/{}/patterns", rule.id) }
+                    confirm="Delete this trigger?"
+                    can_delete={ can_edit }/>
+            </td>
+            <td>
+                <DeletableItems
+                    items={ rule.responses.iter().map(|r| (r.id, describe_response(r))) }
+                    url={ &format!("/rules/{}/responses", rule.id) }
+                    confirm="Delete this response?"
+                    can_delete={ can_edit }/>
+            </td>
+        </tr>
+    }
+}
+
+#[component]
+fn Checkbox(name: &str, label: &str, checked: bool) -> HtmlFragment {
+    if checked {
+        html! {
+            <label><input type="checkbox" name={ name } checked="checked" />{ label }</label>
+        }
+    } else {
+        html! {
+            <label><input type="checkbox" name={ name } />{ label }</label>
+        }
+    }
+}
+
+/// Short descriptions of the non-default settings of a rule.
+fn describe_settings(settings: &RuleSettings) -> Vec<String> {
+    let mut description = Vec::new();
+    if settings.normalize {
+        description.push("ignores case, accents and punctuation".to_string());
+    }
+    if settings.priority != 0 {
+        description.push(format!("priority {}", settings.priority));
+    }
+    if settings.shuffle {
+        description.push("shuffles its responses".to_string());
+    }
+    if settings.stop_processing {
+        description.push("stops further matches".to_string());
+    }
+    let cooldowns = [
+        (settings.cooldowns.global, "globally"),
+        (settings.cooldowns.channel, "per channel"),
+        (settings.cooldowns.user, "per user"),
+    ];
+    for (seconds, scope) in cooldowns {
+        if seconds > 0 {
+            description.push(format!("{seconds}s cooldown {scope}"));
+        }
+    }
+    let scope = &settings.scope;
+    if !scope.allowed_channels.is_empty() {
+        description.push(format!(
+            "only in channels {}",
+            join_ids(&scope.allowed_channels)
+        ));
+    }
+    if !scope.denied_channels.is_empty() {
+        description.push(format!(
+            "not in channels {}",
+            join_ids(&scope.denied_channels)
+        ));
+    }
+    description
+}
+
+fn join_ids(ids: &[u64]) -> String {
+    ids.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn describe_response(response: &Response) -> String {
+    let mut notes = Vec::new();
+    if response.kind != ResponseKind::default() {
+        notes.push(response.kind.to_string());
+    }
+    if !response.embed_title.is_empty() {
+        notes.push(format!("titled {:?}", response.embed_title));
+    }
+    if response.weight != 1 {
+        notes.push(format!("weight {}", response.weight));
+    }
+    if notes.is_empty() {
+        response.response.clone()
+    } else {
+        format!("{} ({})", response.response, notes.join(", "))
+    }
+}
+
+#[component]
+fn KindOption<K: Display + PartialEq>(kind: K, selected: K) -> HtmlFragment {
+    if kind == selected {
+        html! { <option value={ kind } selected="selected">{ kind }</option> }
+    } else {
+        html! { <option value={ kind }>{ kind }</option> }
+    }
+}
+
+#[component]
+pub fn PatternInput(pattern: &str, kind: PatternKind) -> HtmlFragment {
+    html! {
+        <div style="display: flex;">
+            <input name="patterns" placeholder="pattern" value={ pattern } />
+            <select name="kinds">
+                <KindOption :for={option in PatternKind::ALL} kind={ option } selected={ kind } />
+            </select>
+            <button hx-delete="/delete" hx-target="closest div" hx-swap="delete">"❌"</button>
+        </div>
+    }
+}
+
+#[component]
+pub fn ResponseInput(response: &NewResponse) -> HtmlFragment {
+    html! {
+        <div class="response">
+            <div style="display: flex;">
+                <input name="responses" placeholder="response, emoji or file name" value={ response.response } />
+                <select name="response_kinds">
+                    <KindOption :for={option in ResponseKind::ALL} kind={ option } selected={ response.kind } />
+                </select>
+                <input name="weights" type="number" min="0" max={ MAX_WEIGHT } placeholder="weight" value={ response.weight } />
+                <button hx-delete="/delete" hx-target="closest .response" hx-swap="delete">"❌"</button>
             </div>
+            <div style="display: flex;">
+                <input name="embed_titles" placeholder="embed title" value={ response.embed_title } />
+                <input name="embed_images" placeholder="embed image URL" value={ response.embed_image } />
+            </div>
+        </div>
+    }
+}
+
+/// The uploaded files `file` responses can send, with a form to upload more if `can_edit`.
+#[component]
+pub fn FilesTable(files: &[File], errors: &[String], can_edit: bool) -> HtmlFragment {
+    html! {
+        <div id="files">
+            <h2>"Files"</h2>
+            <DeletableItems
+                items={ files.iter().map(|f| (f.id, format!("{} ({} KiB)", f.name, (f.size + 1023) / 1024))) }
+                url="/files"
+                confirm="Delete this file?"
+                can_delete={ can_edit }/>
+            <p :for={error in errors} style="color: red;">{ error }</p>
+            <UploadForm shown={ can_edit } />
+        </div>
+    }
+}
+
+#[component]
+fn UploadForm(shown: bool) -> HtmlFragment {
+    if shown {
+        html! {
+            <form hx-post="/files" hx-encoding="multipart/form-data" hx-target="#files" hx-swap="outerHTML">
+                <input type="file" name="file" />
+                <button>"Upload"</button>
+            </form>
+        }
+    } else {
+        html! {}
+    }
+}
+
+#[component]
+pub fn AddRuleButton(shown: bool) -> HtmlFragment {
+    if shown {
+        html! {
+            <tbody id="add-new-rule">
+                <tr>
+                    <td colspan="3">
+                        <button hx-get="/new-rule-form" hx-target="#add-new-rule" hx-swap="beforebegin">"Add +"</button>
+                    </td>
+                </tr>
+            </tbody>
+        }
+    } else {
+        html! {}
+    }
+}
+
+/// Form rows for creating a rule, or editing the rule with `rule_id`.
+#[component]
+pub fn RuleEditor(
+    form_id: &str,
+    name: &str,
+    patterns: &[(String, PatternKind)],
+    responses: &[NewResponse],
+    settings: &RuleSettings,
+    rule_id: Option<i64>,
+    errors: &[String],
+) -> HtmlFragment {
+    let (method, url, submit) = match rule_id {
+        Some(id) => ("hx-put", format!("/rules/{id}"), "Save"),
+        None => ("hx-post", "/rules".to_string(), "Create"),
+    };
+    let scope = &settings.scope;
+
+    html! {
+        <tr id={ form_id }>
+            <td>
+                <input name="name" placeholder="name" value={ name } />
+                <Checkbox name="normalize" label="Ignore case, accents and punctuation"
+                    checked={ settings.normalize } />
+                <input name="priority" type="number" min={ -MAX_PRIORITY } max={ MAX_PRIORITY } placeholder="priority"
+                    value={ settings.priority } />
+                <Checkbox name="stop_processing" label="Stop processing other rules"
+                    checked={ settings.stop_processing } />
+                <small>"Cooldowns in seconds: global, per channel, per user"</small>
+                <div style="display: flex;">
+                    <input name="global_cooldown" type="number" min="0" placeholder="global"
+                        value={ settings.cooldowns.global } />
+                    <input name="channel_cooldown" type="number" min="0" placeholder="channel"
+                        value={ settings.cooldowns.channel } />
+                    <input name="user_cooldown" type="number" min="0" placeholder="user"
+                        value={ settings.cooldowns.user } />
+                </div>
+                <small>"Where it answers, by channel id: only in these, not in these"</small>
+                <input name="allowed_channels" placeholder="any channel"
+                    value={ join_ids(&scope.allowed_channels) } />
+                <input name="denied_channels" placeholder="no channels"
+                    value={ join_ids(&scope.denied_channels) } />
             </td>
             <td>
-                <TableWihtSingleColumn items={ &rule.patterns }/>
+                <PatternInput :for={(pattern, kind) in patterns} pattern={ pattern } kind={ *kind } />
+                <button hx-get="/pattern-input" hx-swap="beforebegin">"Add another trigger"</button>
             </td>
             <td>
-                <TableWihtSingleColumn items={ &rule.responses }/>
+                <ResponseInput :for={response in responses} response={ response } />
+                <Checkbox name="shuffle" label="Give every response before repeating one"
+                    checked={ settings.shuffle } />
+                <button hx-get="/response-input" hx-swap="beforebegin">"Add another response"</button>
+            </td>
+        </tr>
+        <tr :for={error in errors}>
+            <td colspan="3" style="color: red;">{ error }</td>
+        </tr>
+        <tr>
+            <td colspan="3">
+                <button {method}={ url } hx-target="closest tbody" hx-include="#{form_id}">{ submit }</button>
             </td>
         </tr>
     }
 }
+
+/// A rule snapshot from the audit log as JSON, or a dash if there is none.
+fn snapshot(rule: &Option<Rule>) -> String {
+    rule.as_ref()
+        .and_then(|rule| serde_json::to_string_pretty(rule).ok())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn format_time(timestamp: i64) -> String {
+    Timestamp::from_unix_timestamp(timestamp)
+        .map_or_else(|_| timestamp.to_string(), |t| t.to_string())
+}
+
+#[component]
+fn ChangeRow(change: &RuleChange) -> HtmlFragment {
+    let when = format_time(change.changed_at);
+    let rule = match change.after.as_ref().or(change.before.as_ref()) {
+        Some(rule) => format!("#{} {}", change.rule_id, rule.name),
+        None => format!("#{}", change.rule_id),
+    };
+    html! {
+        <tr id="change{change.id}">
+            <td>{ when }</td>
+            <td><a href="/audit?user={change.changed_by}">{ change.changed_by }</a></td>
+            <td><a href="/audit?rule={change.rule_id}">{ rule }</a></td>
+            <td>{ change.kind }</td>
+            <td>
+                <details>
+                    <summary>"before and after"</summary>
+                    <pre>{ snapshot(&change.before) }</pre>
+                    <pre>{ snapshot(&change.after) }</pre>
+                </details>
+            </td>
+        </tr>
+    }
+}
+
+/// The audit log, newest change first.
+#[component]
+pub fn AuditTable(changes: &[RuleChange]) -> HtmlFragment {
+    html! {
+        <table>
+            <thead>
+                <tr>
+                    <th>"when"</th>
+                    <th>"who"</th>
+                    <th>"rule"</th>
+                    <th>"change"</th>
+                    <th>"rule before and after"</th>
+                </tr>
+            </thead>
+            <tbody>
+                <ChangeRow :for={change in changes} change={ change } />
+            </tbody>
+        </table>
+    }
+}
+
+/// A rule as lines of text, to compare revisions of it.
+fn rule_lines(rule: &Rule) -> Vec<String> {
+    let mut lines = vec![format!("name: {}", rule.name)];
+    for pattern in &rule.patterns {
+        lines.push(format!("trigger: {} ({})", pattern.pattern, pattern.kind));
+    }
+    for response in &rule.responses {
+        lines.push(format!("response: {}", describe_response(response)));
+    }
+    for setting in describe_settings(&rule.settings) {
+        lines.push(format!("setting: {setting}"));
+    }
+    if rule.locked {
+        lines.push("locked".to_string());
+    }
+    lines
+}
+
+#[component]
+fn DiffRow(line: &DiffLine) -> HtmlFragment {
+    let (sign, color, text) = match line {
+        DiffLine::Same(text) => (' ', "inherit", text),
+        DiffLine::Removed(text) => ('-', "red", text),
+        DiffLine::Added(text) => ('+', "green", text),
+    };
+    html! {
+        <div style="color: {color}; font-family: monospace; white-space: pre-wrap;">{ format!("{sign} {text}") }</div>
+    }
+}
+
+#[component]
+fn RevertButton(change: &RuleChange, shown: bool) -> HtmlFragment {
+    if shown && change.after.is_some() {
+        html! {
+            <form method="post" action="/rules/{change.rule_id}/revert/{change.id}">
+                <button>"Revert to this revision"</button>
+            </form>
+        }
+    } else {
+        html! {}
+    }
+}
+
+/// A change to a rule, with what it changed.
+#[component]
+fn Revision(change: &RuleChange, can_revert: bool) -> HtmlFragment {
+    let old = change.before.as_ref().map(rule_lines).unwrap_or_default();
+    let new = change.after.as_ref().map(rule_lines).unwrap_or_default();
+    let summary = format!(
+        "{} {} by {}",
+        format_time(change.changed_at),
+        change.kind,
+        change.changed_by
+    );
+    html! {
+        <section id="change{change.id}">
+            <h3>{ summary }</h3>
+            <DiffRow :for={line in diff_lines(&old, &new)} line={ &line } />
+            <RevertButton change={ change } shown={ can_revert } />
+        </section>
+    }
+}
+
+/// Every change to a rule, newest first. The newest revision is the current rule,
+/// so only older ones can be reverted to.
+#[component]
+pub fn RuleHistory(history: &[RuleChange], can_revert: bool) -> HtmlFragment {
+    html! {
+        <Revision :for={(i, change) in history.iter().enumerate()} change={ change }
+            can_revert={ can_revert && i > 0 } />
+    }
+}
+
+/// A page of its own for an error, for requests that didn't come from htmx.
+#[component]
+pub fn ErrorPage(message: &str) -> HtmlFragment {
+    html! {
+        <!DOCTYPE html>
+        <html lang="en">
+
+            <head>
+                <title>{ "Slackbot" }</title>
+                <meta charset="utf-8" />
+                <link rel="stylesheet" href="https://unpkg.com/missing.css@1.0.9/dist/missing.min.css" />
+            </head>
+
+            <body>
+                <h1>{ message }</h1>
+                <p><a href="/">"Back to the rules"</a></p>
+            </body>
+
+        </html>
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::db::Cooldowns;
+
+/// The bot sends at most this many responses per channel within `CHANNEL_RATE_WINDOW`.
+const CHANNEL_RATE_LIMIT: usize = 5;
+const CHANNEL_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+    Global,
+    Channel(u64),
+    User(u64),
+}
+
+/// Remembers when rules answered, to enforce their cooldowns and the per-channel rate limit.
+#[derive(Default)]
+pub struct CooldownTracker {
+    /// When a rule may answer again within a scope.
+    quiet_until: HashMap<(i64, Scope), Instant>,
+    /// When the bot recently answered in each channel, oldest first.
+    channel_responses: HashMap<u64, VecDeque<Instant>>,
+}
+
+impl CooldownTracker {
+    /// Checks whether a rule may answer `user` in `channel` right now, and if so records that it did.
+    pub fn try_respond(
+        &mut self,
+        rule_id: i64,
+        cooldowns: &Cooldowns,
+        channel: u64,
+        user: u64,
+        now: Instant,
+    ) -> bool {
+        self.quiet_until.retain(|_, until| *until > now);
+        let recent = self.channel_responses.entry(channel).or_default();
+        while recent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= CHANNEL_RATE_WINDOW)
+        {
+            recent.pop_front();
+        }
+
+        let scopes = [
+            (Scope::Global, cooldowns.global),
+            (Scope::Channel(channel), cooldowns.channel),
+            (Scope::User(user), cooldowns.user),
+        ];
+        let cooling_down = scopes
+            .iter()
+            .any(|(scope, _)| self.quiet_until.contains_key(&(rule_id, *scope)));
+        if cooling_down || recent.len() >= CHANNEL_RATE_LIMIT {
+            return false;
+        }
+
+        recent.push_back(now);
+        for (scope, seconds) in scopes {
+            if seconds > 0 {
+                let until = now + Duration::from_secs(seconds.into());
+                self.quiet_until.insert((rule_id, scope), until);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldowns_apply_per_scope() {
+        let mut tracker = CooldownTracker::default();
+        let cooldowns = Cooldowns {
+            global: 0,
+            channel: 30,
+            user: 60,
+        };
+        let start = Instant::now();
+        assert!(tracker.try_respond(1, &cooldowns, 10, 100, start));
+        // Same channel, other user
+        assert!(!tracker.try_respond(1, &cooldowns, 10, 200, start));
+        // Other channel, same user
+        assert!(!tracker.try_respond(1, &cooldowns, 20, 100, start));
+        // Other channel, other user, and other rules are not affected
+        assert!(tracker.try_respond(1, &cooldowns, 20, 200, start));
+        assert!(tracker.try_respond(2, &cooldowns, 10, 100, start));
+
+        let later = start + Duration::from_secs(30);
+        assert!(tracker.try_respond(1, &cooldowns, 10, 300, later));
+        assert!(!tracker.try_respond(1, &cooldowns, 30, 100, later));
+    }
+
+    #[test]
+    fn channels_are_rate_limited() {
+        let mut tracker = CooldownTracker::default();
+        let start = Instant::now();
+        for rule in 0..CHANNEL_RATE_LIMIT as i64 {
+            assert!(tracker.try_respond(rule, &Cooldowns::default(), 10, 100, start));
+        }
+        assert!(!tracker.try_respond(99, &Cooldowns::default(), 10, 100, start));
+        assert!(tracker.try_respond(99, &Cooldowns::default(), 20, 100, start));
+        let later = start + CHANNEL_RATE_WINDOW;
+        assert!(tracker.try_respond(99, &Cooldowns::default(), 10, 100, later));
+    }
+}
@@ -1,6 +1,48 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt::{self, Display};
+use std::str::FromStr;
 
-use sqlx::{Pool, Sqlite, SqlitePool};
+use serde::{Deserialize, Serialize};
+use sqlx::error::ErrorKind;
+use sqlx::migrate::MigrateError;
+use sqlx::{FromRow, Pool, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("not found")]
+    NotFound,
+    /// A unique, foreign key or other constraint refused the change.
+    #[error("conflicts with what is stored: {0}")]
+    Constraint(String),
+    #[error("couldn't reach the database: {0}")]
+    Connection(sqlx::Error),
+    #[error("couldn't migrate the database: {0}")]
+    Migration(#[from] MigrateError),
+    #[error("couldn't store a snapshot of a rule: {0}")]
+    Snapshot(#[from] serde_json::Error),
+    #[error("database query failed: {0}")]
+    Query(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => DbError::NotFound,
+            sqlx::Error::Database(e) if !matches!(e.kind(), ErrorKind::Other) => {
+                DbError::Constraint(e.message().to_string())
+            }
+            sqlx::Error::Configuration(_)
+            | sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => DbError::Connection(error),
+            error => DbError::Query(error),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Db {
@@ -10,179 +52,1744 @@ pub struct Db {
 struct DBRule {
     id: i64,
     name: String,
+    normalize: bool,
+    priority: i64,
+    stop_processing: bool,
+    global_cooldown: u32,
+    channel_cooldown: u32,
+    user_cooldown: u32,
+    shuffle: bool,
+    locked: bool,
     updated_by: String,
     updated_at: i64,
 }
 
 #[allow(dead_code)]
+#[derive(FromRow)]
 struct DBPattern {
     id: i64,
     pattern: String,
+    kind: String,
     rule_id: i64,
     updated_by: String,
     updated_at: i64,
 }
 
+struct DBRuleChange {
+    id: i64,
+    rule_id: i64,
+    kind: String,
+    before: Option<String>,
+    after: Option<String>,
+    changed_by: String,
+    changed_at: i64,
+}
+
 #[allow(dead_code)]
+#[derive(FromRow)]
 struct DBResponse {
     id: i64,
     response: String,
+    weight: u32,
+    kind: String,
+    embed_title: String,
+    embed_image: String,
     rule_id: i64,
     updated_by: String,
     updated_at: i64,
 }
 
+/// How a pattern is matched against a message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PatternKind {
+    #[default]
+    Substring,
+    WholeWord,
+    Regex,
+    Glob,
+}
+
+impl PatternKind {
+    pub const ALL: [PatternKind; 4] = [
+        PatternKind::Substring,
+        PatternKind::WholeWord,
+        PatternKind::Regex,
+        PatternKind::Glob,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PatternKind::Substring => "substring",
+            PatternKind::WholeWord => "whole-word",
+            PatternKind::Regex => "regex",
+            PatternKind::Glob => "glob",
+        }
+    }
+}
+
+impl Display for PatternKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PatternKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PatternKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("Unknown pattern kind: {s}"))
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Pattern {
+    pub id: i64,
+    pub pattern: String,
+    pub kind: PatternKind,
+}
+
+impl From<DBPattern> for Pattern {
+    fn from(p: DBPattern) -> Self {
+        Pattern {
+            id: p.id,
+            pattern: p.pattern,
+            kind: p.kind.parse().unwrap_or_default(),
+        }
+    }
+}
+
+/// How a response is sent to Discord.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseKind {
+    /// A message in the channel.
+    #[default]
+    Message,
+    /// A reply to the triggering message.
+    Reply,
+    /// An emoji reaction on the triggering message, the response being the emoji.
+    Reaction,
+    /// An embed with the response as its description.
+    Embed,
+    /// An uploaded file, the response being its name.
+    File,
+}
+
+impl ResponseKind {
+    pub const ALL: [ResponseKind; 5] = [
+        ResponseKind::Message,
+        ResponseKind::Reply,
+        ResponseKind::Reaction,
+        ResponseKind::Embed,
+        ResponseKind::File,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseKind::Message => "message",
+            ResponseKind::Reply => "reply",
+            ResponseKind::Reaction => "reaction",
+            ResponseKind::Embed => "embed",
+            ResponseKind::File => "file",
+        }
+    }
+}
+
+impl Display for ResponseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ResponseKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResponseKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("Unknown response kind: {s}"))
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub id: i64,
+    pub response: String,
+    /// How likely this response is picked relative to the others of its rule, 0 meaning never.
+    pub weight: u32,
+    pub kind: ResponseKind,
+    /// Title and image URL of embeds, empty for other kinds.
+    pub embed_title: String,
+    pub embed_image: String,
+}
+
+impl From<&DBResponse> for Response {
+    fn from(r: &DBResponse) -> Self {
+        Response {
+            id: r.id,
+            response: r.response.clone(),
+            weight: r.weight,
+            kind: r.kind.parse().unwrap_or_default(),
+            embed_title: r.embed_title.clone(),
+            embed_image: r.embed_image.clone(),
+        }
+    }
+}
+
+/// A response as entered in the web UI, before it is stored.
 #[derive(Clone, Debug)]
+pub struct NewResponse {
+    pub response: String,
+    pub weight: u32,
+    pub kind: ResponseKind,
+    pub embed_title: String,
+    pub embed_image: String,
+}
+
+impl Default for NewResponse {
+    fn default() -> Self {
+        NewResponse {
+            response: String::new(),
+            weight: 1,
+            kind: ResponseKind::default(),
+            embed_title: String::new(),
+            embed_image: String::new(),
+        }
+    }
+}
+
+impl From<Response> for NewResponse {
+    fn from(r: Response) -> Self {
+        NewResponse {
+            response: r.response,
+            weight: r.weight,
+            kind: r.kind,
+            embed_title: r.embed_title,
+            embed_image: r.embed_image,
+        }
+    }
+}
+
+/// An uploaded file that `file` responses can send, without its contents.
+#[derive(Clone, Debug)]
+pub struct File {
+    pub id: i64,
+    pub name: String,
+    pub size: i64,
+}
+
+/// Per-rule options that change how a rule is matched and answered.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleSettings {
+    /// Match on case-folded, NFKC-normalized text without diacritics, punctuation or extra spaces.
+    pub normalize: bool,
+    /// Rules with a higher priority are tried first.
+    pub priority: i64,
+    /// With the all-matches policy, don't answer with any rule after this one.
+    pub stop_processing: bool,
+    pub cooldowns: Cooldowns,
+    /// Go through every response, in random order, before giving any of them again in a channel.
+    pub shuffle: bool,
+    pub scope: RuleScope,
+}
+
+/// Where a rule answers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleScope {
+    /// The only guild the rule answers in, or none to answer in every guild.
+    pub guild_id: Option<u64>,
+    /// If any, the only channels the rule answers in.
+    pub allowed_channels: Vec<u64>,
+    /// Channels the rule never answers in.
+    pub denied_channels: Vec<u64>,
+}
+
+impl RuleScope {
+    pub fn includes_guild(&self, guild_id: Option<u64>) -> bool {
+        self.guild_id.is_none() || self.guild_id == guild_id
+    }
+
+    pub fn includes_channel(&self, channel_id: u64) -> bool {
+        !self.denied_channels.contains(&channel_id)
+            && (self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id))
+    }
+
+    /// Whether the rule answers messages in `channel_id` of `guild_id`, `None` meaning a DM.
+    pub fn includes(&self, guild_id: Option<u64>, channel_id: u64) -> bool {
+        self.includes_guild(guild_id) && self.includes_channel(channel_id)
+    }
+}
+
+/// How many seconds a rule stays quiet after answering, 0 meaning no cooldown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cooldowns {
+    /// After answering anywhere.
+    pub global: u32,
+    /// After answering in the same channel.
+    pub channel: u32,
+    /// After answering the same user.
+    pub user: u32,
+}
+
+impl From<&DBRule> for RuleSettings {
+    fn from(rule: &DBRule) -> Self {
+        RuleSettings {
+            normalize: rule.normalize,
+            priority: rule.priority,
+            stop_processing: rule.stop_processing,
+            cooldowns: Cooldowns {
+                global: rule.global_cooldown,
+                channel: rule.channel_cooldown,
+                user: rule.user_cooldown,
+            },
+            shuffle: rule.shuffle,
+            // Stored in tables of its own, see `Db::get_scopes`
+            scope: RuleScope::default(),
+        }
+    }
+}
+
+/// Which rules answer a message when several of them match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchPolicy {
+    /// Only the matching rule with the highest priority.
+    #[default]
+    FirstMatch,
+    /// Every matching rule, in priority order, up to the first one that stops processing.
+    AllMatches,
+    /// The rule whose matched pattern is the longest.
+    LongestPattern,
+}
+
+impl MatchPolicy {
+    pub const ALL: [MatchPolicy; 3] = [
+        MatchPolicy::FirstMatch,
+        MatchPolicy::AllMatches,
+        MatchPolicy::LongestPattern,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchPolicy::FirstMatch => "first-match",
+            MatchPolicy::AllMatches => "all-matches",
+            MatchPolicy::LongestPattern => "longest-pattern",
+        }
+    }
+}
+
+impl Display for MatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MatchPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| format!("Unknown match policy: {s}"))
+    }
+}
+
+/// What someone may do with the rules of a guild in the web UI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Can look at the rules.
+    Viewer,
+    /// Can also change them, except locked ones, and upload files.
+    Editor,
+    /// Can change every rule and lock them.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Editor, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn can_edit(&self, rule: &Rule) -> bool {
+        match self {
+            Role::Viewer => false,
+            Role::Editor => !rule.locked,
+            Role::Admin => true,
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("Unknown role: {s}"))
+    }
+}
+
+/// The roles given out in a guild, to users and to everyone with a Discord role.
+#[derive(Clone, Debug, Default)]
+pub struct GuildAccess {
+    pub users: HashMap<u64, Role>,
+    pub roles: HashMap<u64, Role>,
+}
+
+impl GuildAccess {
+    /// The highest role a member with `role_ids` has been given, if any.
+    pub fn role_of(&self, user_id: u64, role_ids: &[u64]) -> Option<Role> {
+        let from_roles = role_ids.iter().filter_map(|id| self.roles.get(id));
+        self.users
+            .get(&user_id)
+            .into_iter()
+            .chain(from_roles)
+            .max()
+            .copied()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Rule {
     pub id: i64,
     pub name: String,
-    pub patterns: Vec<String>,
-    pub responses: Vec<String>,
+    pub patterns: Vec<Pattern>,
+    pub responses: Vec<Response>,
+    pub settings: RuleSettings,
+    /// Only admins may change locked rules.
+    pub locked: bool,
     pub updated_by: String,
     pub updated_at: i64,
 }
 
+/// What a change did to a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+    Lock,
+    Unlock,
+    /// Went back to an earlier revision.
+    Revert,
+}
+
+impl ChangeKind {
+    pub const ALL: [ChangeKind; 6] = [
+        ChangeKind::Create,
+        ChangeKind::Update,
+        ChangeKind::Delete,
+        ChangeKind::Lock,
+        ChangeKind::Unlock,
+        ChangeKind::Revert,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Create => "create",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Lock => "lock",
+            ChangeKind::Unlock => "unlock",
+            ChangeKind::Revert => "revert",
+        }
+    }
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ChangeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ChangeKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("Unknown change kind: {s}"))
+    }
+}
+
+/// Who made the changes the bot makes on its own, which no Discord user id can be mistaken for.
+pub const SYSTEM: &str = "system";
+
+/// An entry of the audit log, with the rule as it was before and after the change.
+#[derive(Clone, Debug)]
+pub struct RuleChange {
+    pub id: i64,
+    pub rule_id: i64,
+    pub kind: ChangeKind,
+    /// `None` for rules that were just created.
+    pub before: Option<Rule>,
+    /// `None` for rules that were deleted.
+    pub after: Option<Rule>,
+    /// The Discord user id of whoever made the change, or [`SYSTEM`].
+    pub changed_by: String,
+    pub changed_at: i64,
+}
+
+impl From<DBRuleChange> for RuleChange {
+    fn from(c: DBRuleChange) -> Self {
+        let snapshot =
+            |json: Option<String>| json.and_then(|json| serde_json::from_str(&json).ok());
+        RuleChange {
+            id: c.id,
+            rule_id: c.rule_id,
+            kind: c.kind.parse().unwrap_or(ChangeKind::Update),
+            before: snapshot(c.before),
+            after: snapshot(c.after),
+            changed_by: c.changed_by,
+            changed_at: c.changed_at,
+        }
+    }
+}
+
 impl Db {
-    pub async fn new() -> Self {
-        let db_url = env::var("DATABASE_URL").expect("Provide DATABASE_URL env variable");
-        let pool = SqlitePool::connect(&db_url).await.unwrap();
-        Self { pool }
+    /// Connects to `DATABASE_URL` and brings it up to date with the migrations.
+    pub async fn new() -> Result<Self, DbError> {
+        let db_url = env::var("DATABASE_URL").map_err(|_| {
+            DbError::Connection(sqlx::Error::Configuration(
+                "Provide DATABASE_URL env variable".into(),
+            ))
+        })?;
+        Self::connect(&db_url).await
+    }
+
+    /// Connects to the database at `db_url` and brings it up to date with the migrations.
+    pub async fn connect(db_url: &str) -> Result<Self, DbError> {
+        let pool = SqlitePool::connect(db_url)
+            .await
+            .map_err(DbError::Connection)?;
+        sqlx::migrate!().run(&pool).await?;
+        Ok(Self { pool })
     }
 
-    pub async fn get_rule(&self, id: i64) -> Rule {
-        // TODO: return Result
+    pub async fn get_rule(&self, id: i64) -> Result<Rule, DbError> {
+        Self::fetch_rule(&mut *self.pool.acquire().await?, id).await
+    }
+
+    /// Reads a rule on `conn`, so it can be part of a transaction.
+    async fn fetch_rule(conn: &mut SqliteConnection, id: i64) -> Result<Rule, DbError> {
         let db_rule = sqlx::query_as!(
             DBRule,
-            "SELECT id, name, updated_by, updated_at FROM rules WHERE id = ?",
+            r#"SELECT id, name, normalize AS "normalize: bool", priority,
+                stop_processing AS "stop_processing: bool", global_cooldown AS "global_cooldown: u32",
+                channel_cooldown AS "channel_cooldown: u32", user_cooldown AS "user_cooldown: u32",
+                shuffle AS "shuffle: bool", locked AS "locked: bool", updated_by, updated_at
+            FROM rules WHERE id = ?"#,
             id
         )
-        .fetch_one(&self.pool)
-        .await
-        .unwrap();
-
-        let patterns: Vec<String> =
-            sqlx::query!("SELECT pattern FROM patterns WHERE rule_id = ?", id)
-                .fetch_all(&self.pool)
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|r| r.pattern)
-                .collect();
-
-        let responses: Vec<String> =
-            sqlx::query!("SELECT response FROM responses WHERE rule_id = ?", id)
-                .fetch_all(&self.pool)
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|r| r.response)
-                .collect();
-
-        Rule {
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let patterns: Vec<Pattern> = sqlx::query_as!(
+            DBPattern,
+            "SELECT id AS \"id!\", pattern, kind, rule_id, updated_by, updated_at FROM patterns WHERE rule_id = ?",
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(Pattern::from)
+        .collect();
+
+        let responses: Vec<Response> = sqlx::query_as!(
+            DBResponse,
+            r#"SELECT id AS "id!", response, weight AS "weight: u32", kind, embed_title, embed_image, rule_id,
+                updated_by, updated_at
+            FROM responses WHERE rule_id = ?"#,
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(Response::from)
+        .collect();
+
+        let mut settings = RuleSettings::from(&db_rule);
+        settings.scope = Self::get_scope(conn, id).await?;
+
+        Ok(Rule {
+            settings,
             id: db_rule.id,
             name: db_rule.name,
             patterns,
             responses,
+            locked: db_rule.locked,
             updated_by: db_rule.updated_by,
             updated_at: db_rule.updated_at,
+        })
+    }
+
+    /// Returns every rule, whichever guild it belongs to.
+    pub async fn get_rules(&self) -> Result<Vec<Rule>, DbError> {
+        let db_rules = sqlx::query_as!(
+            DBRule,
+            r#"SELECT id, name, normalize AS "normalize: bool", priority,
+                stop_processing AS "stop_processing: bool", global_cooldown AS "global_cooldown: u32",
+                channel_cooldown AS "channel_cooldown: u32", user_cooldown AS "user_cooldown: u32",
+                shuffle AS "shuffle: bool", locked AS "locked: bool", updated_by, updated_at
+            FROM rules ORDER BY priority DESC, id"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        self.with_patterns_and_responses(db_rules, None).await
+    }
+
+    /// Returns the rules of a guild.
+    pub async fn get_guild_rules(&self, guild_id: u64) -> Result<Vec<Rule>, DbError> {
+        let guild_id = guild_id as i64;
+        let db_rules = sqlx::query_as!(
+            DBRule,
+            r#"SELECT id AS "id!", name, normalize AS "normalize: bool", priority,
+                stop_processing AS "stop_processing: bool", global_cooldown AS "global_cooldown: u32",
+                channel_cooldown AS "channel_cooldown: u32", user_cooldown AS "user_cooldown: u32",
+                shuffle AS "shuffle: bool", locked AS "locked: bool", updated_by, updated_at
+            FROM rules WHERE id IN (SELECT rule_id FROM rule_guilds WHERE guild_id = ?)
+            ORDER BY priority DESC, id"#,
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        self.with_patterns_and_responses(db_rules, Some(guild_id))
+            .await
+    }
+
+    /// Returns the guild a rule belongs to, `None` if it answers in every guild or doesn't exist.
+    pub async fn get_rule_guild(&self, id: i64) -> Result<Option<u64>, DbError> {
+        let guild_id =
+            sqlx::query_scalar!("SELECT guild_id FROM rule_guilds WHERE rule_id = ?", id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(guild_id.map(|guild_id| guild_id as u64))
+    }
+
+    /// Moves the rules that answer in every guild into one guild, along with the files
+    /// uploaded before files belonged to a guild. Files named like one already in the guild
+    /// stay where they are.
+    pub async fn adopt_unscoped_rules(&self, guild_id: u64) -> Result<(), DbError> {
+        let file_guild_id = guild_id as i64;
+        sqlx::query!(
+            "UPDATE OR IGNORE files SET guild_id = ? WHERE guild_id IS NULL",
+            file_guild_id
+        )
+        .execute(&self.pool)
+        .await?;
+        let unscoped = self
+            .get_rules()
+            .await?
+            .into_iter()
+            .filter(|rule| rule.settings.scope.guild_id.is_none());
+        for rule in unscoped {
+            let mut tx = self.pool.begin().await?;
+            let before = Self::fetch_rule(&mut tx, rule.id).await?;
+            if before.settings.scope.guild_id.is_some() {
+                continue;
+            }
+            let mut scope = before.settings.scope.clone();
+            scope.guild_id = Some(guild_id);
+            Self::set_scope(&mut tx, before.id, &scope).await?;
+            let after = Self::fetch_rule(&mut tx, before.id).await?;
+            Self::insert_change(
+                &mut tx,
+                ChangeKind::Update,
+                Some(&before),
+                Some(&after),
+                SYSTEM,
+            )
+            .await?;
+            tx.commit().await?;
         }
+        Ok(())
     }
 
-    pub async fn get_rules(&self) -> Vec<Rule> {
-        let db_rules =
-            sqlx::query_as!(DBRule, "SELECT id, name, updated_by, updated_at FROM rules")
-                .fetch_all(&self.pool)
-                .await
-                .unwrap();
+    /// Adds the patterns, responses and scopes to rules, loading only those of the rules
+    /// of `guild_id` if given.
+    async fn with_patterns_and_responses(
+        &self,
+        db_rules: Vec<DBRule>,
+        guild_id: Option<i64>,
+    ) -> Result<Vec<Rule>, DbError> {
         let db_patterns = sqlx::query_as!(
             DBPattern,
-            "SELECT id, pattern, rule_id, updated_by, updated_at FROM patterns"
+            r#"SELECT id AS "id!", pattern, kind, rule_id, updated_by, updated_at FROM patterns
+            WHERE ? IS NULL OR rule_id IN (SELECT rule_id FROM rule_guilds WHERE guild_id = ?)"#,
+            guild_id,
+            guild_id
         )
         .fetch_all(&self.pool)
-        .await
-        .unwrap();
+        .await?;
 
         let db_reponses = sqlx::query_as!(
             DBResponse,
-            "SELECT id, response, rule_id, updated_by, updated_at FROM responses"
+            r#"SELECT id AS "id!", response, weight AS "weight: u32", kind, embed_title, embed_image,
+                rule_id, updated_by, updated_at
+            FROM responses
+            WHERE ? IS NULL OR rule_id IN (SELECT rule_id FROM rule_guilds WHERE guild_id = ?)"#,
+            guild_id,
+            guild_id
         )
         .fetch_all(&self.pool)
-        .await
-        .unwrap();
+        .await?;
 
+        let mut scopes = self.get_scopes(guild_id).await?;
         let mut rules = Vec::new();
 
         for db_rule in db_rules {
+            let mut settings = RuleSettings::from(&db_rule);
+            settings.scope = scopes.remove(&db_rule.id).unwrap_or_default();
             let rule = Rule {
+                settings,
                 id: db_rule.id,
                 name: db_rule.name,
                 patterns: db_patterns
                     .iter()
                     .filter(|p| p.rule_id == db_rule.id)
-                    .map(|p| p.pattern.clone())
+                    .map(|p| Pattern {
+                        id: p.id,
+                        pattern: p.pattern.clone(),
+                        kind: p.kind.parse().unwrap_or_default(),
+                    })
                     .collect(),
                 responses: db_reponses
                     .iter()
                     .filter(|r| r.rule_id == db_rule.id)
-                    .map(|r| r.response.clone())
+                    .map(Response::from)
                     .collect(),
+                locked: db_rule.locked,
                 updated_by: db_rule.updated_by,
                 updated_at: db_rule.updated_at,
             };
             rules.push(rule);
         }
 
-        rules
+        Ok(rules)
+    }
+
+    /// Returns the scope of every rule that doesn't answer everywhere, by rule id,
+    /// or only of the rules of `guild_id` if given.
+    async fn get_scopes(&self, guild_id: Option<i64>) -> Result<HashMap<i64, RuleScope>, DbError> {
+        let mut scopes: HashMap<i64, RuleScope> = HashMap::new();
+        let guilds = sqlx::query!(
+            "SELECT rule_id, guild_id FROM rule_guilds WHERE ? IS NULL OR guild_id = ?",
+            guild_id,
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        for r in guilds {
+            scopes.entry(r.rule_id).or_default().guild_id = Some(r.guild_id as u64);
+        }
+
+        let channels = sqlx::query!(
+            r#"SELECT rule_id, channel_id, allowed AS "allowed: bool" FROM rule_channels
+            WHERE ? IS NULL OR rule_id IN (SELECT rule_id FROM rule_guilds WHERE guild_id = ?)"#,
+            guild_id,
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        for r in channels {
+            let scope = scopes.entry(r.rule_id).or_default();
+            if r.allowed {
+                scope.allowed_channels.push(r.channel_id as u64);
+            } else {
+                scope.denied_channels.push(r.channel_id as u64);
+            }
+        }
+        Ok(scopes)
+    }
+
+    /// Returns the scope of one rule.
+    async fn get_scope(conn: &mut SqliteConnection, rule_id: i64) -> Result<RuleScope, DbError> {
+        let guild_id = sqlx::query_scalar!(
+            "SELECT guild_id FROM rule_guilds WHERE rule_id = ?",
+            rule_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        let mut scope = RuleScope {
+            guild_id: guild_id.map(|guild_id| guild_id as u64),
+            ..RuleScope::default()
+        };
+
+        let channels = sqlx::query!(
+            r#"SELECT channel_id, allowed AS "allowed: bool" FROM rule_channels WHERE rule_id = ?"#,
+            rule_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        for r in channels {
+            if r.allowed {
+                scope.allowed_channels.push(r.channel_id as u64);
+            } else {
+                scope.denied_channels.push(r.channel_id as u64);
+            }
+        }
+        Ok(scope)
+    }
+
+    /// Replaces the scope of a rule.
+    async fn set_scope(
+        conn: &mut SqliteConnection,
+        rule_id: i64,
+        scope: &RuleScope,
+    ) -> Result<(), DbError> {
+        sqlx::query!("DELETE FROM rule_guilds WHERE rule_id = ?", rule_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM rule_channels WHERE rule_id = ?", rule_id)
+            .execute(&mut *conn)
+            .await?;
+
+        if let Some(guild_id) = scope.guild_id {
+            let guild_id = guild_id as i64;
+            sqlx::query!(
+                "INSERT INTO rule_guilds (rule_id, guild_id) VALUES (?, ?)",
+                rule_id,
+                guild_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let channels = scope
+            .allowed_channels
+            .iter()
+            .map(|id| (id, true))
+            .chain(scope.denied_channels.iter().map(|id| (id, false)));
+        for (channel_id, allowed) in channels {
+            let channel_id = *channel_id as i64;
+            sqlx::query!(
+                "INSERT OR REPLACE INTO rule_channels (rule_id, channel_id, allowed) VALUES (?, ?, ?)",
+                rule_id,
+                channel_id,
+                allowed
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a counter that is bumped on every change to rules, patterns or responses.
+    pub async fn get_rules_revision(&self) -> Result<i64, DbError> {
+        let revision = sqlx::query_scalar!("SELECT revision FROM rules_revision")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(revision)
     }
 
+    /// Creates a rule with its patterns and responses in a single transaction.
     pub async fn create_rule(
         &self,
         name: String,
-        patterns: Vec<String>,
-        responses: Vec<String>,
-    ) -> Rule {
-        let id = sqlx::query!(
-            "INSERT INTO rules (name, updated_by) VALUES (?, ?)",
+        patterns: Vec<(String, PatternKind)>,
+        responses: Vec<NewResponse>,
+        settings: RuleSettings,
+        updated_by: &str,
+    ) -> Result<Rule, DbError> {
+        let mut tx = self.pool.begin().await?;
+
+        let db_rule = sqlx::query_as!(
+            DBRule,
+            r#"INSERT INTO rules (name, normalize, priority, stop_processing, global_cooldown, channel_cooldown,
+                user_cooldown, shuffle, updated_by)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id, name, normalize AS "normalize: bool", priority,
+                stop_processing AS "stop_processing: bool", global_cooldown AS "global_cooldown: u32",
+                channel_cooldown AS "channel_cooldown: u32", user_cooldown AS "user_cooldown: u32",
+                shuffle AS "shuffle: bool", locked AS "locked: bool", updated_by, updated_at"#,
+            name,
+            settings.normalize,
+            settings.priority,
+            settings.stop_processing,
+            settings.cooldowns.global,
+            settings.cooldowns.channel,
+            settings.cooldowns.user,
+            settings.shuffle,
+            updated_by
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let patterns = Self::insert_patterns(&mut tx, db_rule.id, &patterns, updated_by).await?;
+        let responses = Self::insert_responses(&mut tx, db_rule.id, &responses, updated_by).await?;
+        Self::set_scope(&mut tx, db_rule.id, &settings.scope).await?;
+
+        let rule = Rule {
+            settings: RuleSettings {
+                scope: settings.scope,
+                ..RuleSettings::from(&db_rule)
+            },
+            id: db_rule.id,
+            name: db_rule.name,
+            patterns,
+            responses,
+            locked: db_rule.locked,
+            updated_by: db_rule.updated_by,
+            updated_at: db_rule.updated_at,
+        };
+        Self::insert_change(&mut tx, ChangeKind::Create, None, Some(&rule), updated_by).await?;
+
+        tx.commit().await?;
+        Ok(rule)
+    }
+
+    /// Inserts the patterns of a rule in one statement, returning them as stored.
+    async fn insert_patterns(
+        conn: &mut SqliteConnection,
+        rule_id: i64,
+        patterns: &[(String, PatternKind)],
+        updated_by: &str,
+    ) -> Result<Vec<Pattern>, DbError> {
+        if patterns.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO patterns (pattern, kind, rule_id, updated_by) ",
+        );
+        query.push_values(patterns, |mut row, (pattern, kind)| {
+            row.push_bind(pattern)
+                .push_bind(kind.as_str())
+                .push_bind(rule_id)
+                .push_bind(updated_by);
+        });
+        query.push(" RETURNING id, pattern, kind, rule_id, updated_by, updated_at");
+        let patterns = query
+            .build_query_as::<DBPattern>()
+            .fetch_all(&mut *conn)
+            .await?;
+        Ok(patterns.into_iter().map(Pattern::from).collect())
+    }
+
+    /// Inserts the responses of a rule in one statement, returning them as stored.
+    async fn insert_responses(
+        conn: &mut SqliteConnection,
+        rule_id: i64,
+        responses: &[NewResponse],
+        updated_by: &str,
+    ) -> Result<Vec<Response>, DbError> {
+        if responses.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO responses (response, weight, kind, embed_title, embed_image, rule_id, updated_by) ",
+        );
+        query.push_values(responses, |mut row, response| {
+            row.push_bind(&response.response)
+                .push_bind(response.weight)
+                .push_bind(response.kind.as_str())
+                .push_bind(&response.embed_title)
+                .push_bind(&response.embed_image)
+                .push_bind(rule_id)
+                .push_bind(updated_by);
+        });
+        query.push(
+            " RETURNING id, response, weight, kind, embed_title, embed_image, rule_id, updated_by, updated_at",
+        );
+        let responses = query
+            .build_query_as::<DBResponse>()
+            .fetch_all(&mut *conn)
+            .await?;
+        Ok(responses.iter().map(Response::from).collect())
+    }
+
+    /// Replaces the name, patterns and responses of an existing rule in a single transaction.
+    pub async fn update_rule(
+        &self,
+        id: i64,
+        name: String,
+        patterns: Vec<(String, PatternKind)>,
+        responses: Vec<NewResponse>,
+        settings: RuleSettings,
+        updated_by: &str,
+    ) -> Result<Rule, DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, id).await?;
+        Self::replace_rule(&mut tx, id, name, patterns, responses, settings, updated_by).await?;
+        let rule = Self::fetch_rule(&mut tx, id).await?;
+        Self::insert_change(
+            &mut tx,
+            ChangeKind::Update,
+            Some(&before),
+            Some(&rule),
+            updated_by,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(rule)
+    }
+
+    /// Returns a rule as it was after one of its changes. Fails with `DbError::NotFound` if there
+    /// is no such change or it left no rule behind.
+    pub async fn get_revision(&self, rule_id: i64, change_id: i64) -> Result<Rule, DbError> {
+        let after = sqlx::query_scalar!(
+            "SELECT after FROM rule_changes WHERE id = ? AND rule_id = ?",
+            change_id,
+            rule_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .ok_or(DbError::NotFound)?;
+        Ok(serde_json::from_str(&after)?)
+    }
+
+    /// Turns a rule back into an earlier revision of it, in a change of its own.
+    pub async fn revert_rule(
+        &self,
+        id: i64,
+        revision: Rule,
+        changed_by: &str,
+    ) -> Result<Rule, DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, id).await?;
+        let mut settings = revision.settings;
+        // Reverting doesn't move a rule to another guild
+        settings.scope.guild_id = before.settings.scope.guild_id;
+        let patterns = revision
+            .patterns
+            .into_iter()
+            .map(|p| (p.pattern, p.kind))
+            .collect();
+        let responses = revision
+            .responses
+            .into_iter()
+            .map(NewResponse::from)
+            .collect();
+        Self::replace_rule(
+            &mut tx,
+            id,
+            revision.name,
+            patterns,
+            responses,
+            settings,
+            changed_by,
+        )
+        .await?;
+        let rule = Self::fetch_rule(&mut tx, id).await?;
+        Self::insert_change(
+            &mut tx,
+            ChangeKind::Revert,
+            Some(&before),
+            Some(&rule),
+            changed_by,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(rule)
+    }
+
+    /// Overwrites a rule with its patterns, responses and scope on `conn`.
+    async fn replace_rule(
+        conn: &mut SqliteConnection,
+        id: i64,
+        name: String,
+        patterns: Vec<(String, PatternKind)>,
+        responses: Vec<NewResponse>,
+        settings: RuleSettings,
+        updated_by: &str,
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            "UPDATE rules SET name = ?, normalize = ?, priority = ?, stop_processing = ?, global_cooldown = ?,
+                channel_cooldown = ?, user_cooldown = ?, shuffle = ?, updated_by = ?,
+                updated_at = strftime('%s', 'now')
+            WHERE id = ?",
             name,
-            "user"
+            settings.normalize,
+            settings.priority,
+            settings.stop_processing,
+            settings.cooldowns.global,
+            settings.cooldowns.channel,
+            settings.cooldowns.user,
+            settings.shuffle,
+            updated_by,
+            id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query!("DELETE FROM patterns WHERE rule_id = ?", id)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query!("DELETE FROM responses WHERE rule_id = ?", id)
+            .execute(&mut *conn)
+            .await?;
+
+        Self::insert_patterns(conn, id, &patterns, updated_by).await?;
+        Self::insert_responses(conn, id, &responses, updated_by).await?;
+
+        Self::set_scope(conn, id, &settings.scope).await?;
+
+        Ok(())
+    }
+
+    /// Deletes a rule together with its patterns and responses.
+    pub async fn delete_rule(&self, id: i64, changed_by: &str) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, id).await?;
+        sqlx::query!("DELETE FROM rules WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        Self::insert_change(&mut tx, ChangeKind::Delete, Some(&before), None, changed_by).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn delete_pattern(
+        &self,
+        rule_id: i64,
+        id: i64,
+        changed_by: &str,
+    ) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, rule_id).await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM patterns WHERE id = ? AND rule_id = ?",
+            id,
+            rule_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Nothing changed, so there is nothing to record
+        if deleted.rows_affected() == 0 {
+            return Ok(());
+        }
+        let after = Self::fetch_rule(&mut tx, rule_id).await?;
+        Self::insert_change(
+            &mut tx,
+            ChangeKind::Update,
+            Some(&before),
+            Some(&after),
+            changed_by,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn delete_response(
+        &self,
+        rule_id: i64,
+        id: i64,
+        changed_by: &str,
+    ) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, rule_id).await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM responses WHERE id = ? AND rule_id = ?",
+            id,
+            rule_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Nothing changed, so there is nothing to record
+        if deleted.rows_affected() == 0 {
+            return Ok(());
+        }
+        let after = Self::fetch_rule(&mut tx, rule_id).await?;
+        Self::insert_change(
+            &mut tx,
+            ChangeKind::Update,
+            Some(&before),
+            Some(&after),
+            changed_by,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Appends a change to the audit log on `conn`, in the transaction making the change.
+    /// The guild is taken from the rule.
+    async fn insert_change(
+        conn: &mut SqliteConnection,
+        kind: ChangeKind,
+        before: Option<&Rule>,
+        after: Option<&Rule>,
+        changed_by: &str,
+    ) -> Result<(), DbError> {
+        let Some(rule) = after.or(before) else {
+            return Ok(());
+        };
+        let guild_id = rule.settings.scope.guild_id.map(|id| id as i64);
+        let kind = kind.as_str();
+        let before = before.map(serde_json::to_string).transpose()?;
+        let after = after.map(serde_json::to_string).transpose()?;
+        sqlx::query!(
+            "INSERT INTO rule_changes (rule_id, guild_id, kind, before, after, changed_by)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            rule.id,
+            guild_id,
+            kind,
+            before,
+            after,
+            changed_by
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the audit log of a guild, newest first, optionally only about one rule
+    /// or the changes by one user.
+    pub async fn get_rule_changes(
+        &self,
+        guild_id: u64,
+        rule_id: Option<i64>,
+        changed_by: Option<&str>,
+    ) -> Result<Vec<RuleChange>, DbError> {
+        let guild_id = guild_id as i64;
+        Ok(sqlx::query_as!(
+            DBRuleChange,
+            "SELECT id, rule_id, kind, before, after, changed_by, changed_at FROM rule_changes
+            WHERE guild_id = ? AND (? IS NULL OR rule_id = ?) AND (? IS NULL OR changed_by = ?)
+            ORDER BY id DESC",
+            guild_id,
+            rule_id,
+            rule_id,
+            changed_by,
+            changed_by
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(RuleChange::from)
+        .collect())
+    }
+
+    /// Returns every change to a rule in a guild, newest first, also once the rule is deleted.
+    /// Each change that left a rule behind holds a revision of it.
+    pub async fn get_rule_history(
+        &self,
+        guild_id: u64,
+        rule_id: i64,
+    ) -> Result<Vec<RuleChange>, DbError> {
+        let guild_id = guild_id as i64;
+        Ok(sqlx::query_as!(
+            DBRuleChange,
+            "SELECT id AS \"id!\", rule_id, kind, before, after, changed_by, changed_at FROM rule_changes
+            WHERE guild_id = ? AND rule_id = ? ORDER BY id DESC",
+            guild_id,
+            rule_id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(RuleChange::from)
+        .collect())
+    }
+
+    pub async fn create_token(
+        &self,
+        token: &str,
+        user: &str,
+        guild_id: u64,
+        role: Role,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let role = role.as_str();
+        sqlx::query!(
+            "INSERT INTO tokens (token, user, guild_id, role) VALUES (?, ?, ?, ?)",
+            token,
+            user,
+            guild_id,
+            role
         )
         .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Consumes a token, returning the user, guild and role it was issued to
+    /// if it is younger than `max_age` seconds.
+    pub async fn take_token(
+        &self,
+        token: &str,
+        max_age: i64,
+    ) -> Result<Option<(String, u64, Role)>, DbError> {
+        let mut tx = self.pool.begin().await?;
+
+        let user = sqlx::query!(
+            "SELECT user, guild_id, role FROM tokens WHERE token = ? AND created_at > strftime('%s', 'now') - ?",
+            token,
+            max_age
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM tokens WHERE token = ?", token)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(user.map(|r| (r.user, r.guild_id as u64, parse_role(&r.role))))
+    }
+
+    pub async fn create_session(
+        &self,
+        session: &str,
+        user: &str,
+        guild_id: u64,
+        role: Role,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let role = role.as_str();
+        sqlx::query!(
+            "INSERT INTO sessions (session, user, guild_id, role) VALUES (?, ?, ?, ?)",
+            session,
+            user,
+            guild_id,
+            role
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the user, guild and role of a session that is younger than `max_age` seconds.
+    pub async fn get_session_user(
+        &self,
+        session: &str,
+        max_age: i64,
+    ) -> Result<Option<(String, u64, Role)>, DbError> {
+        Ok(sqlx::query!(
+            "SELECT user, guild_id, role FROM sessions WHERE session = ? AND created_at > strftime('%s', 'now') - ?",
+            session,
+            max_age
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|r| (r.user, r.guild_id as u64, parse_role(&r.role))))
+    }
+
+    pub async fn delete_expired_tokens(
+        &self,
+        token_max_age: i64,
+        session_max_age: i64,
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            "DELETE FROM tokens WHERE created_at <= strftime('%s', 'now') - ?",
+            token_max_age
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM sessions WHERE created_at <= strftime('%s', 'now') - ?",
+            session_max_age
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the match policy of every guild that has one configured.
+    pub async fn get_match_policies(&self) -> Result<HashMap<u64, MatchPolicy>, DbError> {
+        let policies = sqlx::query!("SELECT guild_id, match_policy FROM guild_settings")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                (
+                    r.guild_id as u64,
+                    r.match_policy.parse().unwrap_or_default(),
+                )
+            })
+            .collect();
+        Ok(policies)
+    }
+
+    pub async fn set_match_policy(
+        &self,
+        guild_id: u64,
+        policy: MatchPolicy,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let policy = policy.as_str();
+        sqlx::query!(
+            "INSERT INTO guild_settings (guild_id, match_policy) VALUES (?, ?)
+            ON CONFLICT (guild_id) DO UPDATE SET match_policy = excluded.match_policy",
+            guild_id,
+            policy
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Takes a draw from the shuffle bag of a rule in a channel. The bag holds how many more
+    /// times each response id is drawn, and `draw` may change it. Reading and writing the bag
+    /// happen in one transaction, so two messages can't draw the same response.
+    pub async fn draw_from_shuffle_bag<T>(
+        &self,
+        rule_id: i64,
+        channel_id: u64,
+        draw: impl FnOnce(&mut Vec<(i64, u32)>) -> T,
+    ) -> Result<T, DbError> {
+        let channel_id = channel_id as i64;
+        let mut tx = self.pool.begin().await?;
+        // Writing first takes the write lock before the bag is read
+        sqlx::query!(
+            "INSERT INTO shuffle_bags (rule_id, channel_id, response_ids) VALUES (?, ?, '')
+            ON CONFLICT (rule_id, channel_id) DO NOTHING",
+            rule_id,
+            channel_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let bag = sqlx::query_scalar!(
+            "SELECT response_ids FROM shuffle_bags WHERE rule_id = ? AND channel_id = ?",
+            rule_id,
+            channel_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut bag = parse_shuffle_bag(&bag);
+        let drawn = draw(&mut bag);
+        let bag = bag
+            .iter()
+            .map(|(id, left)| format!("{id}:{left}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        sqlx::query!(
+            "UPDATE shuffle_bags SET response_ids = ? WHERE rule_id = ? AND channel_id = ?",
+            bag,
+            rule_id,
+            channel_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(drawn)
+    }
+
+    /// Stores a file uploaded in a guild, replacing any file with the same name there.
+    pub async fn create_file(
+        &self,
+        guild_id: u64,
+        name: &str,
+        data: &[u8],
+        updated_by: &str,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        sqlx::query!(
+            "INSERT INTO files (guild_id, name, data, updated_by) VALUES (?, ?, ?, ?)
+            ON CONFLICT (guild_id, name) DO UPDATE SET data = excluded.data,
+                updated_by = excluded.updated_by, updated_at = strftime('%s', 'now')",
+            guild_id,
+            name,
+            data,
+            updated_by
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_files(&self, guild_id: u64) -> Result<Vec<File>, DbError> {
+        let guild_id = guild_id as i64;
+        sqlx::query_as!(
+            File,
+            r#"SELECT id AS "id!", name, length(data) AS "size!: i64" FROM files
+            WHERE guild_id = ? ORDER BY name"#,
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Returns the contents of the file called `name` in a guild, or of one uploaded before
+    /// files belonged to a guild when there is none.
+    pub async fn get_file_data(
+        &self,
+        guild_id: Option<u64>,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        let guild_id = guild_id.map(|guild_id| guild_id as i64);
+        sqlx::query_scalar!(
+            "SELECT data FROM files WHERE guild_id IS ? AND name = ?",
+            guild_id,
+            name
+        )
+        .fetch_optional(&self.pool)
         .await
-        .unwrap()
-        .last_insert_rowid();
+        .map_err(DbError::from)
+    }
 
-        // TODO: bulk inserts https://docs.rs/sqlx-core/latest/sqlx_core/query_builder/struct.QueryBuilder.html#method.push_values
-        for pattern in &patterns {
+    pub async fn delete_file(&self, guild_id: u64, id: i64) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let deleted = sqlx::query!(
+            "DELETE FROM files WHERE id = ? AND guild_id = ?",
+            id,
+            guild_id
+        )
+        .execute(&self.pool)
+        .await?;
+        if deleted.rows_affected() == 0 {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the prefix commands turned off in a guild.
+    pub async fn get_disabled_commands(&self, guild_id: u64) -> Result<Vec<String>, DbError> {
+        let guild_id = guild_id as i64;
+        sqlx::query_scalar!(
+            "SELECT command FROM disabled_commands WHERE guild_id = ?",
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DbError::from)
+    }
+
+    pub async fn set_command_enabled(
+        &self,
+        guild_id: u64,
+        command: &str,
+        enabled: bool,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        if enabled {
             sqlx::query!(
-                "INSERT INTO patterns (pattern, rule_id, updated_by) VALUES (?, ?, ?)",
-                pattern,
-                id,
-                "user"
+                "DELETE FROM disabled_commands WHERE guild_id = ? AND command = ?",
+                guild_id,
+                command
             )
             .execute(&self.pool)
-            .await
-            .unwrap();
-        }
-
-        for response in &responses {
+            .await?;
+        } else {
             sqlx::query!(
-                "INSERT INTO responses (response, rule_id, updated_by) VALUES (?, ?, ?)",
-                response,
-                id,
-                "user"
+                "INSERT OR IGNORE INTO disabled_commands (guild_id, command) VALUES (?, ?)",
+                guild_id,
+                command
             )
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?;
         }
+        Ok(())
+    }
 
-        Rule {
-            id,
-            name,
-            patterns,
-            responses,
-            updated_by: "user".to_string(),
-            updated_at: 0, // TODO real data ?
+    pub async fn set_rule_locked(
+        &self,
+        id: i64,
+        locked: bool,
+        changed_by: &str,
+    ) -> Result<(), DbError> {
+        let mut tx = self.pool.begin().await?;
+        let before = Self::fetch_rule(&mut tx, id).await?;
+        sqlx::query!("UPDATE rules SET locked = ? WHERE id = ?", locked, id)
+            .execute(&mut *tx)
+            .await?;
+        let after = Self::fetch_rule(&mut tx, id).await?;
+        let kind = if locked {
+            ChangeKind::Lock
+        } else {
+            ChangeKind::Unlock
+        };
+        Self::insert_change(&mut tx, kind, Some(&before), Some(&after), changed_by).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_access(&self, guild_id: u64) -> Result<GuildAccess, DbError> {
+        let guild_id = guild_id as i64;
+        let users = sqlx::query!(
+            "SELECT user_id, role FROM access_users WHERE guild_id = ?",
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|r| (r.user_id as u64, parse_role(&r.role)))
+        .collect();
+        let roles = sqlx::query!(
+            "SELECT role_id, role FROM access_roles WHERE guild_id = ?",
+            guild_id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|r| (r.role_id as u64, parse_role(&r.role)))
+        .collect();
+        Ok(GuildAccess { users, roles })
+    }
+
+    /// Gives a user a role in a guild, or takes it away with `None`. Their edit links and
+    /// sessions for the guild end, as those carry the role they had.
+    pub async fn set_user_access(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        role: Option<Role>,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let user_id = user_id as i64;
+        let mut tx = self.pool.begin().await?;
+        match role {
+            Some(role) => {
+                let role = role.as_str();
+                sqlx::query!(
+                    "INSERT INTO access_users (guild_id, user_id, role) VALUES (?, ?, ?)
+                    ON CONFLICT (guild_id, user_id) DO UPDATE SET role = excluded.role",
+                    guild_id,
+                    user_id,
+                    role
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM access_users WHERE guild_id = ? AND user_id = ?",
+                    guild_id,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        let user = user_id.to_string();
+        sqlx::query!(
+            "DELETE FROM tokens WHERE guild_id = ? AND user = ?",
+            guild_id,
+            user
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM sessions WHERE guild_id = ? AND user = ?",
+            guild_id,
+            user
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Gives everyone with a Discord role a role in a guild, or takes it away with `None`.
+    /// Who has the Discord role isn't known here, so every edit link and session for the guild
+    /// ends.
+    pub async fn set_role_access(
+        &self,
+        guild_id: u64,
+        role_id: u64,
+        role: Option<Role>,
+    ) -> Result<(), DbError> {
+        let guild_id = guild_id as i64;
+        let role_id = role_id as i64;
+        let mut tx = self.pool.begin().await?;
+        match role {
+            Some(role) => {
+                let role = role.as_str();
+                sqlx::query!(
+                    "INSERT INTO access_roles (guild_id, role_id, role) VALUES (?, ?, ?)
+                    ON CONFLICT (guild_id, role_id) DO UPDATE SET role = excluded.role",
+                    guild_id,
+                    role_id,
+                    role
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM access_roles WHERE guild_id = ? AND role_id = ?",
+                    guild_id,
+                    role_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
         }
+        sqlx::query!("DELETE FROM tokens WHERE guild_id = ?", guild_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM sessions WHERE guild_id = ?", guild_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Parses a shuffle bag stored as `id:left` pairs.
+fn parse_shuffle_bag(bag: &str) -> Vec<(i64, u32)> {
+    bag.split_whitespace()
+        .filter_map(|entry| {
+            let (id, left) = entry.split_once(':')?;
+            Some((id.parse().ok()?, left.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Reads a stored role, falling back to the least powerful one.
+fn parse_role(role: &str) -> Role {
+    role.parse().unwrap_or(Role::Viewer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffle_bags_count_draws_left() {
+        assert_eq!(parse_shuffle_bag("3:2 5:1"), [(3, 2), (5, 1)]);
+        assert!(parse_shuffle_bag("").is_empty());
+    }
+
+    #[test]
+    fn members_get_their_highest_role() {
+        let access = GuildAccess {
+            users: HashMap::from([(1, Role::Editor)]),
+            roles: HashMap::from([(10, Role::Viewer), (20, Role::Admin)]),
+        };
+        assert_eq!(access.role_of(1, &[]), Some(Role::Editor));
+        assert_eq!(access.role_of(1, &[10]), Some(Role::Editor));
+        assert_eq!(access.role_of(2, &[10, 20]), Some(Role::Admin));
+        assert_eq!(access.role_of(2, &[30]), None);
+    }
+
+    #[test]
+    fn sqlx_errors_say_what_went_wrong() {
+        let error = |e: sqlx::Error| DbError::from(e);
+        assert!(matches!(error(sqlx::Error::RowNotFound), DbError::NotFound));
+        assert!(matches!(
+            error(sqlx::Error::PoolTimedOut),
+            DbError::Connection(_)
+        ));
+        assert!(matches!(
+            error(sqlx::Error::ColumnNotFound("id".to_string())),
+            DbError::Query(_)
+        ));
     }
 }
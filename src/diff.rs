@@ -0,0 +1,70 @@
+/// A line of the difference between two texts.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines of `old` and `new` in order, marking those only in one of them,
+/// so that as many lines as possible are kept the same.
+pub fn diff_lines<'a>(old: &'a [String], new: &'a [String]) -> Vec<DiffLine<'a>> {
+    // kept[i][j] is how many lines old[i..] and new[j..] have in common at most
+    let mut kept = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            kept[i][j] = if old[i] == new[j] {
+                kept[i + 1][j + 1] + 1
+            } else {
+                kept[i + 1][j].max(kept[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Same(&old[i]));
+            i += 1;
+            j += 1;
+        } else if kept[i + 1][j] >= kept[i][j + 1] {
+            diff.push(DiffLine::Removed(&old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(&new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn diff_keeps_common_lines() {
+        let old = lines("name kpop time response-1 response-2");
+        let new = lines("name kpop tijd response-2 response-3");
+        assert_eq!(
+            diff_lines(&old, &new),
+            [
+                DiffLine::Same("name"),
+                DiffLine::Same("kpop"),
+                DiffLine::Removed("time"),
+                DiffLine::Removed("response-1"),
+                DiffLine::Added("tijd"),
+                DiffLine::Same("response-2"),
+                DiffLine::Added("response-3"),
+            ]
+        );
+        assert_eq!(diff_lines(&[], &old[..1]), [DiffLine::Added("name")]);
+    }
+}
@@ -1,22 +1,55 @@
 use anyhow::Result;
 use serenity::{
     async_trait,
-    model::prelude::{ChannelId, GuildId, Message, MessageId, Ready},
+    builder::CreateApplicationCommandOption,
+    model::application::command::{Command, CommandOptionType},
+    model::application::interaction::{
+        application_command::{ApplicationCommandInteraction, CommandDataOption},
+        Interaction, InteractionResponseType,
+    },
+    model::prelude::{
+        AttachmentType, ChannelId, GuildId, Message, MessageId, Permissions, ReactionType, Ready,
+    },
     prelude::*,
 };
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 use thiserror::Error;
+use url::Url;
 
-use crate::{auth, message};
+use crate::{
+    commands,
+    cooldown::CooldownTracker,
+    db::{Db, DbError, NewResponse, PatternKind, ResponseKind, Rule, RuleSettings},
+    message::{self, Reply},
+    validation::{validate_pattern_sizes, validate_patterns, validate_responses, MAX_WEIGHT},
+};
 
-#[allow(dead_code)]
-fn get_guild() -> GuildId {
-    GuildId::from(
-        env::var("DISCORD_GUILD_ID")
-            .expect("Provide DISCORD_GUILD_ID env variable")
-            .parse::<u64>()
-            .expect("DISCORD_GUILD_ID must be integer"),
-    )
+/// Discord refuses messages longer than this.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// The guild rules belonged to before the bot served several, if `DISCORD_GUILD_ID` is set.
+fn get_guild() -> Option<GuildId> {
+    let id = env::var("DISCORD_GUILD_ID").ok()?;
+    Some(GuildId::from(
+        id.parse::<u64>().expect("DISCORD_GUILD_ID must be integer"),
+    ))
+}
+
+pub(crate) fn public_url() -> Url {
+    let url = env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:3000/".to_string());
+    Url::parse(&url).expect("PUBLIC_URL must be a valid URL")
+}
+
+/// Builds the web UI link for an `!edit` token.
+pub(crate) fn edit_link(token: &str) -> Url {
+    let mut url = public_url();
+    url.query_pairs_mut().append_pair("token", token);
+    url
 }
 
 pub async fn send_message(channel: ChannelId, ctx: &Context, message: &str) {
@@ -25,6 +58,65 @@ pub async fn send_message(channel: ChannelId, ctx: &Context, message: &str) {
     }
 }
 
+/// Sends a rule's reply to the message that triggered it.
+async fn send_reply(db: &Db, ctx: &Context, msg: &Message, reply: Reply) {
+    let result = match reply {
+        Reply::Message(text) => return send_message(msg.channel_id, ctx, &text).await,
+        Reply::Inline(text) => msg.reply(ctx, text).await.map(|_| ()),
+        Reply::Reaction(emoji) => match ReactionType::try_from(emoji.as_str()) {
+            Ok(reaction) => msg.react(ctx, reaction).await.map(|_| ()),
+            Err(why) => {
+                println!("Invalid reaction {:?}: {:?}", emoji, why);
+                return;
+            }
+        },
+        Reply::Embed {
+            title,
+            description,
+            image,
+        } => msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title(title).description(description);
+                    if !image.is_empty() {
+                        e.image(image);
+                    }
+                    e
+                })
+            })
+            .await
+            .map(|_| ()),
+        Reply::File(name) => {
+            let data = match db
+                .get_file_data(msg.guild_id.map(|guild| guild.0), &name)
+                .await
+            {
+                Ok(Some(data)) => data,
+                Ok(None) => {
+                    println!("File {:?} no longer exists", name);
+                    return;
+                }
+                Err(why) => {
+                    println!("Error fetching file {:?}: {:?}", name, why);
+                    return;
+                }
+            };
+            let file = AttachmentType::Bytes {
+                data: Cow::Owned(data),
+                filename: name,
+            };
+            msg.channel_id
+                .send_message(&ctx.http, |m| m.add_file(file))
+                .await
+                .map(|_| ())
+        }
+    };
+    if let Err(why) = result {
+        println!("Error sending reply: {:?}", why);
+    }
+}
+
 #[derive(Error, Debug)]
 #[error("No messages found")]
 struct NoMessagesError;
@@ -36,7 +128,7 @@ impl NoMessagesError {
     }
 }
 
-async fn summarize(
+pub(crate) async fn summarize(
     _channel: ChannelId,
     _last_message: MessageId,
     _ctx: &Context,
@@ -63,47 +155,453 @@ fn reverse_messages(messages: Vec<Message>) -> String {
         .collect()
 }
 
-struct Handler;
+fn subcommand<'a>(
+    option: &'a mut CreateApplicationCommandOption,
+    name: &str,
+    description: &str,
+) -> &'a mut CreateApplicationCommandOption {
+    option
+        .name(name)
+        .description(description)
+        .kind(CommandOptionType::SubCommand)
+}
+
+fn rule_id_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("id")
+        .description("Id of the rule, see /rule list")
+        .kind(CommandOptionType::Integer)
+        .required(true)
+}
+
+fn string_option<'a>(
+    option: &'a mut CreateApplicationCommandOption,
+    name: &str,
+    description: &str,
+) -> &'a mut CreateApplicationCommandOption {
+    option
+        .name(name)
+        .description(description)
+        .kind(CommandOptionType::String)
+        .required(true)
+}
+
+fn response_kind_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("kind")
+        .description("How the response is sent, a message by default")
+        .kind(CommandOptionType::String);
+    for kind in ResponseKind::ALL {
+        option.add_string_choice(kind, kind);
+    }
+    option
+}
+
+/// Registers the `/rule` command, which manages rules from Discord instead of the web UI.
+async fn register_commands(ctx: &Context) {
+    let result = Command::set_global_application_commands(&ctx.http, |commands| {
+        commands.create_application_command(|command| {
+            command
+                .name("rule")
+                .description("Manage the rules the bot answers messages with")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .dm_permission(false)
+                .create_option(|o| {
+                    subcommand(o, "add", "Create a rule with a trigger and a response")
+                        .create_sub_option(|o| string_option(o, "name", "Name of the rule"))
+                        .create_sub_option(|o| string_option(o, "trigger", "Pattern to look for"))
+                        .create_sub_option(|o| string_option(o, "response", "What to answer with"))
+                        .create_sub_option(|o| {
+                            o.name("trigger-kind")
+                                .description(
+                                    "How the trigger is matched, as a substring by default",
+                                )
+                                .kind(CommandOptionType::String);
+                            for kind in PatternKind::ALL {
+                                o.add_string_choice(kind, kind);
+                            }
+                            o
+                        })
+                        .create_sub_option(response_kind_option)
+                })
+                .create_option(|o| subcommand(o, "list", "List all rules"))
+                .create_option(|o| {
+                    subcommand(o, "show", "Show the triggers and responses of a rule")
+                        .create_sub_option(rule_id_option)
+                })
+                .create_option(|o| {
+                    subcommand(o, "remove", "Delete a rule").create_sub_option(rule_id_option)
+                })
+                .create_option(|o| {
+                    subcommand(o, "add-response", "Add a response to a rule")
+                        .create_sub_option(rule_id_option)
+                        .create_sub_option(|o| string_option(o, "response", "What to answer with"))
+                        .create_sub_option(response_kind_option)
+                        .create_sub_option(|o| {
+                            o.name("weight")
+                                .description("How likely the response is picked, 1 by default")
+                                .kind(CommandOptionType::Integer)
+                                .min_int_value(0)
+                                .max_int_value(MAX_WEIGHT)
+                        })
+                })
+        })
+    })
+    .await;
+    if let Err(why) = result {
+        println!("Error registering commands: {:?}", why);
+    }
+}
+
+fn string_arg<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|o| o.name == name)?
+        .value
+        .as_ref()?
+        .as_str()
+}
+
+fn integer_arg(options: &[CommandDataOption], name: &str) -> Option<i64> {
+    options
+        .iter()
+        .find(|o| o.name == name)?
+        .value
+        .as_ref()?
+        .as_i64()
+}
+
+fn parsed_arg<T: std::str::FromStr + Default>(options: &[CommandDataOption], name: &str) -> T {
+    string_arg(options, name)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The rule with `id`, if it belongs to the guild.
+async fn find_rule(db: &Db, guild_id: u64, id: i64) -> Result<Option<Rule>, DbError> {
+    if db.get_rule_guild(id).await? != Some(guild_id) {
+        return Ok(None);
+    }
+    db.get_rule(id).await.map(Some)
+}
+
+fn describe_rule(rule: &Rule) -> String {
+    let mut description = format!("**#{} {}**\nTriggers:\n", rule.id, rule.name);
+    for pattern in &rule.patterns {
+        description.push_str(&format!("- `{}` ({})\n", pattern.pattern, pattern.kind));
+    }
+    description.push_str("Responses:\n");
+    for response in &rule.responses {
+        description.push_str(&format!(
+            "- {} ({}, weight {})\n",
+            response.response, response.kind, response.weight
+        ));
+    }
+    description
+}
+
+/// Cuts a message down to what Discord accepts.
+fn truncate_message(mut message: String) -> String {
+    if message.len() > MAX_MESSAGE_LENGTH {
+        let mut end = MAX_MESSAGE_LENGTH - "…".len();
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+        message.push('…');
+    }
+    message
+}
+
+/// Runs a `/rule` subcommand and returns what to tell the user who ran it.
+async fn run_rule_command(db: &Db, command: &ApplicationCommandInteraction) -> Result<String> {
+    let allowed = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|p| p.manage_guild());
+    let Some(guild_id) = command.guild_id.map(|id| id.0) else {
+        return Ok("Rules can only be managed in a server".to_string());
+    };
+    if !allowed {
+        return Ok("You need the Manage Server permission to manage rules".to_string());
+    }
+    let Some(subcommand) = command.data.options.first() else {
+        return Ok("Unknown command".to_string());
+    };
+    let options = &subcommand.options;
+    let user = command.user.id.0.to_string();
+
+    match subcommand.name.as_str() {
+        "add" => {
+            let (Some(name), Some(trigger), Some(response)) = (
+                string_arg(options, "name"),
+                string_arg(options, "trigger"),
+                string_arg(options, "response"),
+            ) else {
+                return Ok("Provide a name, a trigger and a response".to_string());
+            };
+            let patterns = vec![(trigger.to_string(), parsed_arg(options, "trigger-kind"))];
+            let responses = vec![NewResponse {
+                response: response.to_string(),
+                kind: parsed_arg(options, "kind"),
+                ..NewResponse::default()
+            }];
+            let mut settings = RuleSettings::default();
+            settings.scope.guild_id = Some(guild_id);
+            let mut errors = validate_patterns(&patterns, &settings);
+            errors.extend(validate_pattern_sizes(db, None, &patterns, &settings).await?);
+            errors.extend(validate_responses(db, guild_id, &responses).await?);
+            if !errors.is_empty() {
+                return Ok(errors.join("\n"));
+            }
+
+            let rule = db
+                .create_rule(name.to_string(), patterns, responses, settings, &user)
+                .await?;
+            message::reload_rules(db).await?;
+            Ok(format!("Created rule #{} {}", rule.id, rule.name))
+        }
+        "list" => {
+            let rules = db.get_guild_rules(guild_id).await?;
+            if rules.is_empty() {
+                return Ok("There are no rules yet".to_string());
+            }
+            Ok(rules
+                .iter()
+                .map(|rule| {
+                    let patterns: Vec<&str> =
+                        rule.patterns.iter().map(|p| p.pattern.as_str()).collect();
+                    format!("#{} {}: {}\n", rule.id, rule.name, patterns.join(", "))
+                })
+                .collect())
+        }
+        "show" => {
+            let id = integer_arg(options, "id").unwrap_or_default();
+            match find_rule(db, guild_id, id).await? {
+                Some(rule) => Ok(describe_rule(&rule)),
+                None => Ok(format!("There is no rule #{id}")),
+            }
+        }
+        "remove" => {
+            let id = integer_arg(options, "id").unwrap_or_default();
+            match find_rule(db, guild_id, id).await? {
+                Some(rule) => {
+                    db.delete_rule(id, &user).await?;
+                    message::reload_rules(db).await?;
+                    Ok(format!("Deleted rule #{} {}", rule.id, rule.name))
+                }
+                None => Ok(format!("There is no rule #{id}")),
+            }
+        }
+        "add-response" => {
+            let id = integer_arg(options, "id").unwrap_or_default();
+            let Some(rule) = find_rule(db, guild_id, id).await? else {
+                return Ok(format!("There is no rule #{id}"));
+            };
+            let Some(response) = string_arg(options, "response") else {
+                return Ok("Provide a response".to_string());
+            };
+            let weight = integer_arg(options, "weight").map_or(Ok(1), u32::try_from);
+            let Ok(weight) = weight else {
+                return Ok(format!("The weight must be between 0 and {MAX_WEIGHT}"));
+            };
+            let response = NewResponse {
+                response: response.to_string(),
+                kind: parsed_arg(options, "kind"),
+                weight,
+                ..NewResponse::default()
+            };
+            let errors = validate_responses(db, guild_id, std::slice::from_ref(&response)).await?;
+            if !errors.is_empty() {
+                return Ok(errors.join("\n"));
+            }
+
+            let patterns = rule
+                .patterns
+                .into_iter()
+                .map(|p| (p.pattern, p.kind))
+                .collect();
+            let mut responses: Vec<NewResponse> =
+                rule.responses.into_iter().map(NewResponse::from).collect();
+            responses.push(response);
+            let rule = db
+                .update_rule(id, rule.name, patterns, responses, rule.settings, &user)
+                .await?;
+            message::reload_rules(db).await?;
+            Ok(format!(
+                "Added a response to rule #{} {}",
+                rule.id, rule.name
+            ))
+        }
+        _ => Ok("Unknown command".to_string()),
+    }
+}
+
+/// Parses a comma-separated list of user ids from an env variable.
+fn user_ids_from_env(name: &str) -> HashSet<u64> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .unwrap_or_else(|_| panic!("{name} must be a comma-separated list of user ids"))
+        })
+        .collect()
+}
+
+/// Decides whose messages the bot handles, so it never answers itself
+/// and two bots can't keep triggering each other.
+#[derive(Default)]
+struct AuthorFilter {
+    /// Bots that may trigger rules and commands, which other bots can't.
+    allowed_bots: HashSet<u64>,
+    /// Accounts that are always ignored, bots or not.
+    denied: HashSet<u64>,
+}
+
+impl AuthorFilter {
+    fn from_env() -> Self {
+        AuthorFilter {
+            allowed_bots: user_ids_from_env("ALLOWED_BOTS"),
+            denied: user_ids_from_env("DENIED_USERS"),
+        }
+    }
+
+    fn allows(&self, author_id: u64, is_bot: bool, own_id: u64) -> bool {
+        if author_id == own_id || self.denied.contains(&author_id) {
+            return false;
+        }
+        !is_bot || self.allowed_bots.contains(&author_id)
+    }
+}
+
+struct Handler {
+    db: Db,
+    cooldowns: Mutex<CooldownTracker>,
+    authors: AuthorFilter,
+    /// The bot's own user id, known once it is ready.
+    own_id: AtomicU64,
+}
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if msg.content.starts_with("!edit") {
-            send_message(
-                msg.channel_id,
-                &ctx,
-                &format!(
-                    "http://localhost:3000/?token={}",
-                    auth::generate_token(msg.author.id.0)
-                ),
+        let own_id = self.own_id.load(Ordering::Relaxed);
+        if !self.authors.allows(msg.author.id.0, msg.author.bot, own_id) {
+            return;
+        }
+
+        if commands::dispatch(&self.db, &ctx, &msg).await {
+            return;
+        }
+
+        let replies = message::respond(&self.db, &msg, |rule_id, cooldowns| {
+            self.cooldowns.lock().unwrap().try_respond(
+                rule_id,
+                cooldowns,
+                msg.channel_id.0,
+                msg.author.id.0,
+                Instant::now(),
             )
-            .await;
+        })
+        .await;
+        for reply in replies {
+            send_reply(&self.db, &ctx, &msg, reply).await
         }
+    }
 
-        if msg.content.contains("bot, what are they talking about") {
-            if let Ok(message) = summarize(msg.channel_id, msg.id, &ctx).await {
-                send_message(msg.channel_id, &ctx, &message).await
-            }
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let Interaction::ApplicationCommand(command) = interaction else {
+            return;
+        };
+        if command.data.name != "rule" {
+            return;
         }
 
-        if let Some(response) = message::respond(&msg.content) {
-            send_message(msg.channel_id, &ctx, &response).await
+        let content = match run_rule_command(&self.db, &command).await {
+            Ok(content) => truncate_message(content),
+            Err(why) => {
+                println!("Error running command: {:?}", why);
+                "Something went wrong, try again later".to_string()
+            }
+        };
+        let result = command
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|data| data.content(content).ephemeral(true))
+            })
+            .await;
+        if let Err(why) = result {
+            println!("Error responding to command: {:?}", why);
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        self.own_id.store(ready.user.id.0, Ordering::Relaxed);
+        if let Err(why) = message::reload_rules(&self.db).await {
+            println!("Error loading rules: {:?}", why);
+        }
+        register_commands(&ctx).await;
         println!("{} is connected!", ready.user.name);
     }
 }
 
 pub async fn create_client() -> Client {
+    let url = public_url();
+    if url.scheme() != "https" && url.host_str() != Some("localhost") {
+        println!("PUBLIC_URL is not HTTPS, edit tokens will be sent in plain text");
+    }
     let token = env::var("DISCORD_API_TOKEN").expect("Provide DISCORD_API_TOKEN env variable");
     // Set gateway intents, which decides what events the bot will be notified about
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
+    let db = Db::new()
+        .await
+        .unwrap_or_else(|why| panic!("Couldn't open the database: {why}"));
+    if let Some(guild) = get_guild() {
+        if let Err(why) = db.adopt_unscoped_rules(guild.0).await {
+            println!("Error moving rules to DISCORD_GUILD_ID: {:?}", why);
+        }
+    }
+    message::watch_rules(db.clone());
     Client::builder(&token, intents)
-        .event_handler(Handler)
+        .event_handler(Handler {
+            db,
+            cooldowns: Mutex::new(CooldownTracker::default()),
+            authors: AuthorFilter::from_env(),
+            own_id: AtomicU64::new(0),
+        })
         .await
         .expect("Err creating client")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn author_filter_ignores_itself_and_other_bots() {
+        let filter = AuthorFilter {
+            allowed_bots: HashSet::from([2]),
+            denied: HashSet::from([3]),
+        };
+        let own_id = 1;
+        assert!(filter.allows(10, false, own_id));
+        assert!(!filter.allows(own_id, true, own_id));
+        assert!(filter.allows(2, true, own_id));
+        assert!(!filter.allows(20, true, own_id));
+        assert!(!filter.allows(3, false, own_id));
+
+        let default = AuthorFilter::default();
+        assert!(!default.allows(2, true, own_id));
+    }
+}
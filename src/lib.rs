@@ -1,7 +1,12 @@
 mod ai;
 mod auth;
+mod commands;
 mod components;
+mod cooldown;
 mod db;
+mod diff;
 pub mod discord;
 mod message;
+mod template;
+mod validation;
 pub mod web;
@@ -1,51 +1,488 @@
-use dashmap::DashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use aho_corasick::AhoCorasick;
 use lazy_static::lazy_static;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use regex::{Regex, RegexSet};
+use serenity::model::channel::Message;
+use thiserror::Error;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::db::{Cooldowns, Db, DbError, MatchPolicy, PatternKind, ResponseKind, Rule, RuleScope};
+use crate::template::{MessageContext, Template};
+
+const RULES_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 lazy_static! {
-    static ref BOT_RULES: DashMap<String, Vec<String>> = {
-        let rules = DashMap::new();
-        let kpop = vec![
-            String::from("https://youtu.be/9bZkp7q19f0"),
-            String::from("https://youtu.be/POe9SOEKotk"),
-            String::from("https://youtu.be/5UdoUmvu_n8"),
-            String::from("https://youtu.be/2e-Q7GfCGbA"),
-            String::from("https://youtu.be/id6q2EP2UqE"),
-            String::from("https://youtu.be/8dJyRm2jJ-U"),
-            String::from("https://youtu.be/JQGRg8XBnB4"),
-            String::from("https://youtu.be/Hbb5GPxXF1w"),
-            String::from("https://youtu.be/p1bjnyDqI9k"),
-            String::from("https://youtu.be/k6jqx9kZgPM"),
-            String::from("https://youtu.be/z8Eu-HU0sWQ"),
-            String::from("https://youtu.be/eH8jn4W8Bqc"),
-            String::from("https://youtu.be/IHNzOHi8sJs"),
-            String::from("https://youtu.be/WPdWvnAAurg"),
-            String::from("https://youtu.be/gdZLi9oWNZg"),
-            String::from("https://youtu.be/H8kqPkEXP_E"),
-            String::from("https://youtu.be/awkkyBH2zEo"),
-            String::from("https://youtu.be/z3szNvgQxHo"),
-            String::from("https://youtu.be/i0p1bmr0EmE"),
-            String::from("https://youtu.be/WyiIGEHQP8o"),
-            String::from("https://youtu.be/lcRV2gyEfMo"),
-        ];
-        rules.insert(String::from("kpop time"), kpop.clone());
-        rules.insert(String::from("k p o p   t i m e"), kpop.clone());
-        rules.insert(String::from("kpop tijd"), kpop);
-        rules.insert(
-            String::from("hat a week huh"),
-            vec![String::from("https://whataweek.eu")],
-        );
-        rules.insert(
-            String::from("hat a week huh"),
-            vec![String::from("https://whataweek.eu")],
-        );
-        rules.insert(
-            String::from("(╯°□°)╯︵ ┻━┻"),
-            vec![String::from("┬─┬ノ(º_ºノ)")],
-        );
+    static ref RULE_INDEX: RwLock<RuleIndex> = RwLock::new(RuleIndex::default());
+    static ref MATCH_POLICIES: RwLock<HashMap<u64, MatchPolicy>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Error, Debug)]
+pub enum PatternError {
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error("nothing is left of it after normalization")]
+    EmptyAfterNormalization,
+}
+
+/// Why the patterns of all rules together couldn't be compiled, though each compiles alone.
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("too many substring patterns: {0}")]
+    Literals(#[from] aho_corasick::BuildError),
+    #[error("the regex patterns are too big together: {0}")]
+    Regexes(#[from] regex::Error),
+}
+
+/// A pattern ready to be matched against messages.
+#[derive(Clone)]
+pub enum Matcher {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// Compiles a pattern. With `normalize` the pattern is normalized the same way
+    /// messages are, except for regexes which are expected to be written against normalized text.
+    pub fn new(pattern: &str, kind: PatternKind, normalize: bool) -> Result<Self, PatternError> {
+        let pattern = match (normalize, kind) {
+            (false, _) | (true, PatternKind::Regex) => pattern.to_string(),
+            (true, PatternKind::Glob) => normalize_glob(pattern),
+            (true, _) => self::normalize(pattern),
+        };
+        if pattern.is_empty() && normalize {
+            return Err(PatternError::EmptyAfterNormalization);
+        }
+
+        let matcher = match kind {
+            PatternKind::Substring => Matcher::Substring(pattern),
+            PatternKind::WholeWord => Matcher::Regex(Regex::new(&format!(
+                r"\b{{start-half}}{}\b{{end-half}}",
+                regex::escape(&pattern)
+            ))?),
+            PatternKind::Regex => Matcher::Regex(Regex::new(&pattern)?),
+            PatternKind::Glob => Matcher::Regex(Regex::new(&glob_to_regex(&pattern))?),
+        };
+        Ok(matcher)
+    }
+
+    #[cfg(test)]
+    pub fn is_match(&self, message: &str) -> bool {
+        match self {
+            Matcher::Substring(pattern) => message.contains(pattern.as_str()),
+            Matcher::Regex(regex) => regex.is_match(message),
+        }
+    }
+}
+
+/// Translates a glob into an anchored regex: `*` matches any run of characters, `?` a single one.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("(?s)^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Folds case, applies NFKC, strips diacritics and punctuation and collapses whitespace.
+pub fn normalize(text: &str) -> String {
+    fold(text).trim().to_string()
+}
+
+/// Normalizes the literal parts of a glob, keeping its wildcards.
+fn normalize_glob(glob: &str) -> String {
+    let mut normalized = String::new();
+    let mut literal = String::new();
+    for c in glob.chars() {
+        if c == '*' || c == '?' {
+            normalized.push_str(&fold(&literal));
+            normalized.push(c);
+            literal.clear();
+        } else {
+            literal.push(c);
+        }
+    }
+    normalized.push_str(&fold(&literal));
+    normalized.trim().to_string()
+}
+
+fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    let mut after_space = false;
+    for c in text
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .nfc()
+    {
+        if c.is_whitespace() {
+            if !after_space {
+                folded.push(' ');
+            }
+            after_space = true;
+        } else {
+            folded.push(c);
+            after_space = false;
+        }
+    }
+    folded
+}
+
+struct CompiledResponse {
+    id: i64,
+    weight: u32,
+    kind: ResponseKind,
+    template: Template,
+    embed_title: Template,
+    embed_image: String,
+}
+
+/// What to send in answer to a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Message(String),
+    /// A reply to the triggering message.
+    Inline(String),
+    Reaction(String),
+    Embed {
+        title: String,
+        description: String,
+        image: String,
+    },
+    /// The name of an uploaded file.
+    File(String),
+}
+
+struct CompiledRule {
+    id: i64,
+    responses: Vec<CompiledResponse>,
+    stop_processing: bool,
+    cooldowns: Cooldowns,
+    shuffle: bool,
+    scope: RuleScope,
+}
+
+impl CompiledRule {
+    /// Whether any of the responses can ever be picked.
+    fn can_respond(&self) -> bool {
+        self.responses.iter().any(|r| r.weight > 0)
+    }
+}
+
+/// A rule that matched a message, with the text its longest pattern match and capture groups.
+pub struct RuleMatch {
+    rule: Arc<CompiledRule>,
+    captures: Vec<String>,
+}
+
+impl RuleMatch {
+    fn render(&self, response: &CompiledResponse, context: &MessageContext) -> Reply {
+        let captures: Vec<&str> = self.captures.iter().map(String::as_str).collect();
+        let text = response.template.render(context, &captures);
+        match response.kind {
+            ResponseKind::Message => Reply::Message(text),
+            ResponseKind::Reply => Reply::Inline(text),
+            ResponseKind::Reaction => Reply::Reaction(text),
+            ResponseKind::Embed => Reply::Embed {
+                title: response.embed_title.render(context, &captures),
+                description: text,
+                image: response.embed_image.clone(),
+            },
+            ResponseKind::File => Reply::File(text),
+        }
+    }
+}
+
+/// Where the longest match among the patterns of a rule was found.
+#[derive(Clone, Copy)]
+struct Hit {
+    start: usize,
+    end: usize,
+    /// Found in the normalized message text rather than the raw one.
+    normalized: bool,
+    /// Index of the regex that matched, in the `PatternSet` the hit comes from.
+    regex: Option<usize>,
+}
+
+impl Hit {
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    fn record(self, hit: &mut Option<Hit>) {
+        if hit.is_none_or(|longest| longest.len() < self.len()) {
+            *hit = Some(self);
+        }
+    }
+}
+
+/// Every pattern matched against one version of the message text, raw or normalized,
+/// compiled into a single Aho-Corasick automaton for literals and a `RegexSet` for the rest.
+struct PatternSet {
+    literals: AhoCorasick,
+    literal_rules: Vec<usize>,
+    regexes: RegexSet,
+    regex_rules: Vec<usize>,
+    /// The same regexes on their own, to find out where they match.
+    regex_matchers: Vec<Regex>,
+}
+
+impl PatternSet {
+    /// Builds the set from matchers paired with the index of the rule they belong to.
+    fn new(matchers: Vec<(Matcher, usize)>) -> Result<Self, IndexError> {
+        let mut literals = Vec::new();
+        let mut literal_rules = Vec::new();
+        let mut regex_matchers = Vec::new();
+        let mut regex_rules = Vec::new();
+        for (matcher, rule) in matchers {
+            match matcher {
+                Matcher::Substring(pattern) => {
+                    literals.push(pattern);
+                    literal_rules.push(rule);
+                }
+                Matcher::Regex(regex) => {
+                    regex_matchers.push(regex);
+                    regex_rules.push(rule);
+                }
+            }
+        }
+
+        Ok(Self {
+            literals: AhoCorasick::new(literals)?,
+            literal_rules,
+            // The size limit applies to all regexes together, so the set can fail to compile
+            // even though each regex compiled on its own
+            regexes: RegexSet::new(regex_matchers.iter().map(Regex::as_str))?,
+            regex_rules,
+            regex_matchers,
+        })
+    }
+
+    /// Records, for every rule with at least one pattern occurring in `text`, its longest match.
+    fn mark_matching_rules(&self, text: &str, normalized: bool, matched: &mut [Option<Hit>]) {
+        for m in self.literals.find_overlapping_iter(text) {
+            let hit = Hit {
+                start: m.start(),
+                end: m.end(),
+                normalized,
+                regex: None,
+            };
+            hit.record(&mut matched[self.literal_rules[m.pattern().as_usize()]]);
+        }
+        for i in self.regexes.matches(text).iter() {
+            if let Some(m) = self.regex_matchers[i].find(text) {
+                let hit = Hit {
+                    start: m.start(),
+                    end: m.end(),
+                    normalized,
+                    regex: Some(i),
+                };
+                hit.record(&mut matched[self.regex_rules[i]]);
+            }
+        }
+    }
+
+    /// The text of a hit in `text` followed by the capture groups of its regex, if any.
+    fn captures<'t>(&self, hit: Hit, text: &'t str) -> Vec<&'t str> {
+        let captures = hit
+            .regex
+            .and_then(|i| self.regex_matchers[i].captures(text));
+        match captures {
+            Some(captures) => captures
+                .iter()
+                .map(|group| group.map_or("", |m| m.as_str()))
+                .collect(),
+            None => vec![&text[hit.start..hit.end]],
+        }
+    }
+
+    /// Builds the set like `new`, but leaves out the patterns of the rules that keep it from
+    /// compiling, so a few rules can't keep all others from matching. The rules are added in
+    /// the order of `rules`, so the last ones are left out first. Returns the rules left out.
+    fn build_leaving_out(matchers: Vec<(Matcher, usize)>, rules: &[usize]) -> (Self, Vec<usize>) {
+        let only = |rules: &[usize]| {
+            let rules: HashSet<usize> = rules.iter().copied().collect();
+            matchers
+                .iter()
+                .filter(|(_, rule)| rules.contains(rule))
+                .cloned()
+                .collect()
+        };
+        let mut kept = rules.to_vec();
+        let mut left_out = Vec::new();
+        loop {
+            if let Ok(set) = Self::new(only(&kept)) {
+                return (set, left_out);
+            }
+            // The first rules up to `compiles` compile together, adding the next one fails
+            let (mut compiles, mut fails) = (0, kept.len());
+            while fails - compiles > 1 {
+                let middle = (compiles + fails) / 2;
+                if Self::new(only(&kept[..middle])).is_ok() {
+                    compiles = middle;
+                } else {
+                    fails = middle;
+                }
+            }
+            left_out.push(kept.remove(compiles));
+        }
+    }
+}
+
+impl Default for PatternSet {
+    fn default() -> Self {
+        Self::new(Vec::new()).expect("an empty pattern set compiles")
+    }
+}
+
+/// In-memory view of the rules table the bot matches incoming messages against.
+#[derive(Default)]
+pub struct RuleIndex {
+    rules: Vec<Arc<CompiledRule>>,
+    raw: PatternSet,
+    normalized: PatternSet,
+    /// Ids of the rules whose patterns didn't fit in with the others, which never match.
+    left_out: Vec<i64>,
+}
+
+impl RuleIndex {
+    /// Indexes the rules, trying them from the highest priority down and by id within a priority.
+    /// Patterns that don't compile are skipped. All patterns together share one size limit,
+    /// so the most recently updated rules that go over it are left out.
+    pub fn new(mut rules: Vec<Rule>) -> Self {
+        rules.sort_by_key(|rule| (Reverse(rule.settings.priority), rule.id));
+        let mut oldest_first: Vec<usize> = (0..rules.len()).collect();
+        oldest_first.sort_by_key(|&i| (rules[i].updated_at, rules[i].id));
+        let mut raw = Vec::new();
+        let mut normalized = Vec::new();
+        let mut compiled = Vec::with_capacity(rules.len());
+
+        for (i, rule) in rules.into_iter().enumerate() {
+            let normalize = rule.settings.normalize;
+            for p in rule.patterns {
+                match Matcher::new(&p.pattern, p.kind, normalize) {
+                    Ok(matcher) if normalize => normalized.push((matcher, i)),
+                    Ok(matcher) => raw.push((matcher, i)),
+                    Err(why) => println!("Skipping invalid pattern {:?}: {}", p.pattern, why),
+                }
+            }
+            compiled.push(Arc::new(CompiledRule {
+                id: rule.id,
+                responses: rule
+                    .responses
+                    .iter()
+                    .map(|r| CompiledResponse {
+                        id: r.id,
+                        weight: r.weight,
+                        kind: r.kind,
+                        template: compile_template(&r.response),
+                        embed_title: compile_template(&r.embed_title),
+                        embed_image: r.embed_image.clone(),
+                    })
+                    .collect(),
+                stop_processing: rule.settings.stop_processing,
+                cooldowns: rule.settings.cooldowns,
+                shuffle: rule.settings.shuffle,
+                scope: rule.settings.scope,
+            }));
+        }
+
+        let (raw, mut left_out) = PatternSet::build_leaving_out(raw, &oldest_first);
+        let (normalized, left_out_normalized) =
+            PatternSet::build_leaving_out(normalized, &oldest_first);
+        left_out.extend(left_out_normalized);
+        let left_out: Vec<i64> = left_out.into_iter().map(|i| compiled[i].id).collect();
+        for id in &left_out {
+            println!("Leaving out rule #{id}, its patterns are too big together with the others");
+        }
+
+        Self {
+            rules: compiled,
+            raw,
+            normalized,
+            left_out,
+        }
+    }
+
+    /// Ids of the rules left out because their patterns are too big together with the others.
+    pub fn left_out(&self) -> &[i64] {
+        &self.left_out
+    }
+
+    /// Every rule that answers `message`, sent in `channel_id` of `guild_id`, under `policy`.
+    /// Rules scoped elsewhere are left out before the policy picks among the others.
+    pub fn matches(
+        &self,
+        message: &str,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        policy: MatchPolicy,
+    ) -> Vec<RuleMatch> {
+        let normalized = normalize(message);
+        let mut matched = vec![None; self.rules.len()];
+        self.raw.mark_matching_rules(message, false, &mut matched);
+        self.normalized
+            .mark_matching_rules(&normalized, true, &mut matched);
+
+        let mut hits = matched
+            .iter()
+            .enumerate()
+            .filter_map(|(rule, hit)| hit.map(|hit| (rule, hit)))
+            .filter(|(rule, _)| self.rules[*rule].scope.includes(guild_id, channel_id));
+        let rules: Vec<usize> = match policy {
+            MatchPolicy::FirstMatch => hits.next().map(|(rule, _)| rule).into_iter().collect(),
+            MatchPolicy::AllMatches => {
+                let mut rules = Vec::new();
+                for (rule, _) in hits {
+                    rules.push(rule);
+                    if self.rules[rule].stop_processing {
+                        break;
+                    }
+                }
+                rules
+            }
+            // Ties go to the rule that comes first
+            MatchPolicy::LongestPattern => hits
+                .fold(
+                    None,
+                    |longest: Option<(usize, Hit)>, (rule, hit)| match longest {
+                        Some((_, longest_hit)) if longest_hit.len() >= hit.len() => longest,
+                        _ => Some((rule, hit)),
+                    },
+                )
+                .map(|(rule, _)| rule)
+                .into_iter()
+                .collect(),
+        };
+
         rules
-    };
+            .into_iter()
+            .map(|i| {
+                let hit = matched[i].unwrap();
+                let captures = if hit.normalized {
+                    self.normalized.captures(hit, &normalized)
+                } else {
+                    self.raw.captures(hit, message)
+                };
+                RuleMatch {
+                    rule: self.rules[i].clone(),
+                    captures: captures.into_iter().map(String::from).collect(),
+                }
+            })
+            .collect()
+    }
 }
 
 #[allow(dead_code)]
@@ -53,42 +490,195 @@ fn match_message(message: &str, patterns: &[&str]) -> bool {
     patterns.iter().any(|p| message.contains(p))
 }
 
-fn random_choice(v: &[String]) -> &str {
-    v.choose(&mut thread_rng()).unwrap() // todo: empty vector
+fn compile_template(template: &str) -> Template {
+    template.parse().unwrap_or_else(|why| {
+        println!("Sending template {:?} as is: {}", template, why);
+        Template::literal(template)
+    })
 }
 
-pub fn respond(message: &str) -> Option<String> {
-    for entry in BOT_RULES.iter() {
-        let prompt = entry.key();
-        let responses = entry.value();
-        if message.contains(prompt) {
-            return Some(String::from(random_choice(responses)));
+/// Picks a response at random according to the weights, or nothing if all weights are 0.
+fn random_choice(responses: &[CompiledResponse]) -> Option<&CompiledResponse> {
+    responses
+        .choose_weighted(&mut thread_rng(), |r| r.weight)
+        .ok()
+}
+
+/// Takes the next response out of a shuffle bag holding how many more times each response id
+/// is drawn, refilling the bag with every response, as many times as its weight, once it is
+/// empty.
+fn draw_from_bag<'r>(
+    bag: &mut Vec<(i64, u32)>,
+    responses: &'r [CompiledResponse],
+) -> Option<&'r CompiledResponse> {
+    // Responses may have been edited since the bag was filled
+    bag.retain_mut(|(id, left)| match responses.iter().find(|r| r.id == *id) {
+        Some(response) => {
+            *left = (*left).min(response.weight);
+            *left > 0
         }
+        None => false,
+    });
+    if bag.is_empty() {
+        bag.extend(
+            responses
+                .iter()
+                .filter(|r| r.weight > 0)
+                .map(|r| (r.id, r.weight)),
+        );
     }
-    None
+    let (id, left) = bag
+        .choose_weighted_mut(&mut thread_rng(), |(_, left)| *left)
+        .ok()?;
+    let id = *id;
+    *left -= 1;
+    bag.retain(|(_, left)| *left > 0);
+    responses.iter().find(|r| r.id == id)
 }
 
-#[allow(dead_code)]
-fn save_rule(pattern: String, mut responses: Vec<String>) {
-    match BOT_RULES.get_mut(&pattern) {
-        None => {
-            BOT_RULES.insert(pattern, responses);
-        }
-        Some(mut entry) => {
-            let value_ptr = entry.value_mut();
-            (*value_ptr).append(&mut responses);
+/// Draws the next response of a rule from its shuffle bag in a channel.
+async fn draw_shuffled<'r>(
+    db: &Db,
+    rule: &'r CompiledRule,
+    channel_id: u64,
+) -> Result<Option<&'r CompiledResponse>, DbError> {
+    db.draw_from_shuffle_bag(rule.id, channel_id, |bag| {
+        draw_from_bag(bag, &rule.responses)
+    })
+    .await
+}
+
+/// Rebuilds the rule index and reloads the guild match policies from the database.
+/// Call it whenever rules change. If the database fails, the rules loaded before stay.
+pub async fn reload_rules(db: &Db) -> Result<(), DbError> {
+    let rules = db.get_rules().await?;
+    let policies = db.get_match_policies().await?;
+    *RULE_INDEX.write().unwrap() = RuleIndex::new(rules);
+    *MATCH_POLICIES.write().unwrap() = policies;
+    Ok(())
+}
+
+/// Polls the rules revision in the background and reloads the index when it changes,
+/// so edits made from the web UI reach the bot without a restart.
+pub fn watch_rules(db: Db) {
+    tokio::spawn(async move {
+        let mut seen_revision = None;
+        let mut interval = tokio::time::interval(RULES_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let revision = match db.get_rules_revision().await {
+                Ok(revision) => revision,
+                Err(why) => {
+                    println!("Error checking for rule changes: {:?}", why);
+                    continue;
+                }
+            };
+            if seen_revision != Some(revision) {
+                // Try again on the next tick if it fails
+                match reload_rules(&db).await {
+                    Ok(()) => seen_revision = Some(revision),
+                    Err(why) => println!("Error reloading rules: {:?}", why),
+                }
+            }
         }
-    }
+    });
 }
 
-#[allow(dead_code)]
-fn save_rule_single_response(pattern: String, response: String) {
-    save_rule(pattern, vec![response]);
+/// Responses to a message, following the match policy of the guild it was sent in.
+/// Rules only answer if `allow`, called with their id and cooldowns, lets them.
+pub async fn respond(
+    db: &Db,
+    msg: &Message,
+    mut allow: impl FnMut(i64, &Cooldowns) -> bool,
+) -> Vec<Reply> {
+    let policy = msg
+        .guild_id
+        .and_then(|id| MATCH_POLICIES.read().unwrap().get(&id.0).copied())
+        .unwrap_or_default();
+    let context = MessageContext {
+        author: msg
+            .member
+            .as_ref()
+            .and_then(|member| member.nick.clone())
+            .unwrap_or_else(|| msg.author.name.clone()),
+        author_id: msg.author.id.0,
+        channel_id: msg.channel_id.0,
+        timestamp: msg.timestamp.unix_timestamp(),
+    };
+    let guild_id = msg.guild_id.map(|id| id.0);
+    let matches =
+        RULE_INDEX
+            .read()
+            .unwrap()
+            .matches(&msg.content, guild_id, msg.channel_id.0, policy);
+
+    let mut replies = Vec::new();
+    for m in matches {
+        let rule = &m.rule;
+        if !rule.can_respond() || !allow(rule.id, &rule.cooldowns) {
+            continue;
+        }
+        let response = if rule.shuffle {
+            draw_shuffled(db, rule, msg.channel_id.0)
+                .await
+                .unwrap_or_else(|why| {
+                    println!("Error drawing from a shuffle bag: {:?}", why);
+                    random_choice(&rule.responses)
+                })
+        } else {
+            random_choice(&rule.responses)
+        };
+        if let Some(response) = response {
+            replies.push(m.render(response, &context));
+        }
+    }
+    replies
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::{Pattern, Response};
+
+    fn texts(index: &RuleIndex, message: &str, policy: MatchPolicy) -> Vec<String> {
+        index
+            .matches(message, None, 0, policy)
+            .iter()
+            .filter_map(|m| {
+                let response = random_choice(&m.rule.responses)?;
+                match m.render(response, &MessageContext::default()) {
+                    Reply::Message(text) => Some(text),
+                    reply => panic!("Expected a message, got {reply:?}"),
+                }
+            })
+            .collect()
+    }
+
+    fn rule(id: i64, patterns: &[&str], responses: &[&str]) -> Rule {
+        Rule {
+            id,
+            name: format!("rule {id}"),
+            patterns: patterns
+                .iter()
+                .map(|p| Pattern {
+                    id,
+                    pattern: p.to_string(),
+                    kind: PatternKind::Substring,
+                })
+                .collect(),
+            responses: responses
+                .iter()
+                .enumerate()
+                .map(|(i, r)| Response {
+                    id: i as i64,
+                    response: r.to_string(),
+                    weight: 1,
+                    ..Response::default()
+                })
+                .collect(),
+            ..Rule::default()
+        }
+    }
 
     #[test]
     fn match_message_works() {
@@ -97,4 +687,374 @@ mod tests {
         assert!(match_message("Is het al kpop tijd?", patterns));
         assert!(!match_message("It's Britney time", patterns));
     }
+
+    #[test]
+    fn rule_index_responds_from_rules() {
+        let index = RuleIndex::new(vec![
+            rule(
+                1,
+                &["kpop time", "kpop tijd"],
+                &["https://youtu.be/9bZkp7q19f0"],
+            ),
+            rule(2, &["(╯°□°)╯︵ ┻━┻"], &["┬─┬ノ(º_ºノ)"]),
+        ]);
+        assert_eq!(
+            texts(&index, "Is het al kpop tijd?", MatchPolicy::FirstMatch),
+            ["https://youtu.be/9bZkp7q19f0"]
+        );
+        assert_eq!(
+            texts(&index, "(╯°□°)╯︵ ┻━┻", MatchPolicy::FirstMatch),
+            ["┬─┬ノ(º_ºノ)"]
+        );
+        assert!(texts(&index, "It's Britney time", MatchPolicy::FirstMatch).is_empty());
+    }
+
+    #[test]
+    fn whole_word_patterns_respect_word_boundaries() {
+        let matcher = Matcher::new("kpop", PatternKind::WholeWord, false).unwrap();
+        assert!(matcher.is_match("kpop time!"));
+        assert!(matcher.is_match("is it (kpop)?"));
+        assert!(!matcher.is_match("kpoptime"));
+
+        let matcher = Matcher::new("(╯°□°)╯︵ ┻━┻", PatternKind::WholeWord, false).unwrap();
+        assert!(matcher.is_match("ugh (╯°□°)╯︵ ┻━┻"));
+    }
+
+    #[test]
+    fn regex_patterns_work() {
+        let matcher =
+            Matcher::new(r"(?i)^k\s*p\s*o\s*p\s+time", PatternKind::Regex, false).unwrap();
+        assert!(matcher.is_match("KPOP TIME"));
+        assert!(matcher.is_match("k p o p time"));
+        assert!(!matcher.is_match("not kpop time"));
+        assert!(Matcher::new("(unclosed", PatternKind::Regex, false).is_err());
+    }
+
+    #[test]
+    fn glob_patterns_match_the_whole_message() {
+        let matcher = Matcher::new("*hat a week*huh*", PatternKind::Glob, false).unwrap();
+        assert!(matcher.is_match("what a week, huh?"));
+        assert!(matcher.is_match("what a week huh"));
+        assert!(!matcher.is_match("what a day, huh?"));
+
+        let matcher = Matcher::new("kpop t?me", PatternKind::Glob, false).unwrap();
+        assert!(matcher.is_match("kpop tyme"));
+        assert!(!matcher.is_match("is it kpop time"));
+    }
+
+    #[test]
+    fn normalize_folds_variants_together() {
+        assert_eq!(normalize("What a WEEK,  huh?!"), "what a week huh");
+        assert_eq!(normalize("  Ça   va, Zoë? "), "ca va zoe");
+        assert_eq!(normalize("ｋｐｏｐ　ｔｉｍｅ"), "kpop time");
+        assert_eq!(normalize("케이팝 time"), "케이팝 time");
+    }
+
+    #[test]
+    fn normalized_rules_match_variants() {
+        let mut what_a_week = rule(1, &["hat a week, huh"], &["https://whataweek.eu"]);
+        what_a_week.settings.normalize = true;
+        let index = RuleIndex::new(vec![what_a_week]);
+        let policy = MatchPolicy::FirstMatch;
+        assert!(!texts(&index, "WHAT A WEEK HUH", policy).is_empty());
+        assert!(!texts(&index, "what a week... huh", policy).is_empty());
+        assert!(texts(&index, "what a day, huh", policy).is_empty());
+
+        let index = RuleIndex::new(vec![rule(
+            1,
+            &["hat a week, huh"],
+            &["https://whataweek.eu"],
+        )]);
+        assert!(texts(&index, "WHAT A WEEK HUH", policy).is_empty());
+    }
+
+    #[test]
+    fn normalized_patterns_keep_their_kind() {
+        let matcher = Matcher::new("*Kpop T?me*", PatternKind::Glob, true).unwrap();
+        assert!(matcher.is_match(&normalize("Is it KPOP TIME?")));
+
+        assert!(matches!(
+            Matcher::new("(╯°□°)╯︵ ┻━┻", PatternKind::Substring, true),
+            Err(PatternError::EmptyAfterNormalization)
+        ));
+    }
+
+    #[test]
+    fn rule_index_matches_like_a_plain_loop() {
+        let index = RuleIndex::new(vec![
+            rule(1, &["kpop"], &["1"]),
+            rule(2, &["kpop time"], &["2"]),
+        ]);
+        // The first rule wins, even when a later one has a longer overlapping pattern
+        assert_eq!(texts(&index, "kpop time", MatchPolicy::FirstMatch), ["1"]);
+
+        let index = RuleIndex::new(vec![
+            rule(1, &["never"], &["1"]),
+            rule(2, &["time"], &["2"]),
+            rule(3, &["kpop"], &["3"]),
+        ]);
+        assert_eq!(texts(&index, "kpop time", MatchPolicy::FirstMatch), ["2"]);
+        assert!(texts(&RuleIndex::default(), "kpop time", MatchPolicy::FirstMatch).is_empty());
+    }
+
+    #[test]
+    fn priority_decides_between_overlapping_rules() {
+        let mut kpop_time = rule(2, &["kpop time"], &["2"]);
+        kpop_time.settings.priority = 1;
+        let mut kpop = rule(3, &["kpop"], &["3"]);
+        kpop.settings.priority = i64::MIN;
+        // Given in any order, the higher priority rule comes first
+        let index = RuleIndex::new(vec![kpop, kpop_time, rule(1, &["time"], &["1"])]);
+        assert_eq!(texts(&index, "kpop time", MatchPolicy::FirstMatch), ["2"]);
+        assert_eq!(
+            texts(&index, "kpop time", MatchPolicy::AllMatches),
+            ["2", "1", "3"]
+        );
+    }
+
+    #[test]
+    fn all_matches_policy_stops_at_stop_processing_rules() {
+        let mut time = rule(2, &["time"], &["2"]);
+        time.settings.stop_processing = true;
+        let index = RuleIndex::new(vec![
+            rule(1, &["kpop"], &["1"]),
+            time,
+            rule(3, &["kpop time"], &["3"]),
+        ]);
+        assert_eq!(
+            texts(&index, "kpop time", MatchPolicy::AllMatches),
+            ["1", "2"]
+        );
+        assert_eq!(texts(&index, "kpop", MatchPolicy::AllMatches), ["1"]);
+        assert_eq!(texts(&index, "kpop time", MatchPolicy::FirstMatch), ["1"]);
+    }
+
+    #[test]
+    fn rules_only_answer_within_their_scope() {
+        let mut guild = rule(1, &["kpop"], &["1"]);
+        guild.settings.scope = RuleScope {
+            guild_id: Some(10),
+            allowed_channels: vec![],
+            denied_channels: vec![11],
+        };
+        let mut channels = rule(2, &["kpop"], &["2"]);
+        channels.settings.scope.allowed_channels = vec![11, 21];
+        let index = RuleIndex::new(vec![guild, channels, rule(3, &["kpop"], &["3"])]);
+        let texts = |guild_id, channel_id| -> Vec<String> {
+            index
+                .matches("kpop", guild_id, channel_id, MatchPolicy::AllMatches)
+                .iter()
+                .map(|m| m.rule.id.to_string())
+                .collect()
+        };
+        assert_eq!(texts(Some(10), 12), ["1", "3"]);
+        assert_eq!(texts(Some(10), 11), ["2", "3"]);
+        assert_eq!(texts(Some(20), 21), ["2", "3"]);
+        assert_eq!(texts(None, 30), ["3"]);
+        // Out of scope rules don't keep others from answering first
+        let first = index.matches("kpop", Some(20), 30, MatchPolicy::FirstMatch);
+        assert_eq!(first[0].rule.id, 3);
+    }
+
+    #[test]
+    fn longest_pattern_policy_prefers_the_longest_match() {
+        let mut whole_word = rule(3, &["kpop time yet"], &["3"]);
+        whole_word.patterns[0].kind = PatternKind::WholeWord;
+        let index = RuleIndex::new(vec![
+            rule(1, &["kpop"], &["1"]),
+            rule(2, &["kpop time", "is"], &["2"]),
+            whole_word,
+            rule(4, &["it kpop"], &["4"]),
+        ]);
+        let policy = MatchPolicy::LongestPattern;
+        assert_eq!(texts(&index, "is it kpop time", policy), ["2"]);
+        assert_eq!(texts(&index, "is it kpop time yet?", policy), ["3"]);
+        // Ties go to the earlier rule
+        let index = RuleIndex::new(vec![rule(1, &["abcd"], &["1"]), rule(2, &["wxyz"], &["2"])]);
+        assert_eq!(texts(&index, "wxyz abcd", policy), ["1"]);
+    }
+
+    #[test]
+    fn responses_are_rendered_with_the_match() {
+        let mut regex = rule(
+            1,
+            &[r"(\w+) o'clock"],
+            &["{mention}: {1} o'clock is {match}"],
+        );
+        regex.patterns[0].kind = PatternKind::Regex;
+        let mut normalized = rule(2, &["Kpop Time"], &["{match}!"]);
+        normalized.settings.normalize = true;
+        let index = RuleIndex::new(vec![regex, normalized]);
+        let context = MessageContext {
+            author_id: 7,
+            ..MessageContext::default()
+        };
+
+        let matches = index.matches("is it five o'clock yet", None, 0, MatchPolicy::FirstMatch);
+        assert_eq!(
+            matches[0].render(&matches[0].rule.responses[0], &context),
+            Reply::Message("<@7>: five o'clock is five o'clock".to_string())
+        );
+        assert_eq!(
+            texts(&index, "KPOP, TIME?", MatchPolicy::FirstMatch),
+            ["kpop time!"]
+        );
+    }
+
+    #[test]
+    fn shuffle_bag_gives_every_response_before_repeating() {
+        let mut kpop = rule(1, &["kpop"], &["a", "b", "c"]);
+        kpop.responses[1].weight = 2;
+        kpop.responses[2].weight = 0;
+        let index = RuleIndex::new(vec![kpop]);
+        let responses = &index.rules[0].responses;
+
+        let mut bag = Vec::new();
+        for _ in 0..2 {
+            let mut drawn: Vec<i64> = (0..3)
+                .map(|_| draw_from_bag(&mut bag, responses).unwrap().id)
+                .collect();
+            drawn.sort();
+            assert_eq!(drawn, [0, 1, 1]);
+        }
+        // The bag holds each response once, however heavy it is
+        draw_from_bag(&mut bag, responses);
+        assert!(bag.len() <= 2);
+        // Ids of responses that no longer exist are dropped, and counts above the weight shrink
+        let mut bag = vec![(0, 5), (42, 3)];
+        assert_eq!(draw_from_bag(&mut bag, responses).unwrap().id, 0);
+        assert!(bag.is_empty());
+    }
+
+    #[test]
+    fn rules_too_big_together_are_left_out_newest_first() {
+        let big = |id, updated_at| {
+            let mut big = rule(id, &[r"\w{60}"; 3], &[&id.to_string()]);
+            big.updated_at = updated_at;
+            for pattern in &mut big.patterns {
+                pattern.kind = PatternKind::Regex;
+            }
+            big
+        };
+        let mut kpop = rule(3, &["kpop"], &["3"]);
+        kpop.updated_at = 3;
+        let index = RuleIndex::new(vec![big(2, 2), big(1, 1), kpop]);
+        assert_eq!(index.left_out(), [2]);
+        let message = format!("kpop {}", "a".repeat(60));
+        assert_eq!(texts(&index, &message, MatchPolicy::AllMatches), ["1", "3"]);
+    }
+
+    #[test]
+    fn rules_without_responses_stay_quiet() {
+        let mut zero_weight = rule(2, &["time"], &["2"]);
+        zero_weight.responses[0].weight = 0;
+        let index = RuleIndex::new(vec![rule(1, &["kpop"], &[]), zero_weight]);
+        assert!(texts(&index, "kpop time", MatchPolicy::AllMatches).is_empty());
+        assert!(!index.rules[0].can_respond());
+        assert!(draw_from_bag(&mut Vec::new(), &index.rules[1].responses).is_none());
+    }
+
+    #[test]
+    fn responses_are_rendered_by_kind() {
+        let mut kinds = rule(1, &[r"(\w+) time"], &["{1}", "🎵", "{match}", "kpop.gif"]);
+        kinds.patterns[0].kind = PatternKind::Regex;
+        kinds.responses[0].kind = ResponseKind::Reply;
+        kinds.responses[1].kind = ResponseKind::Reaction;
+        kinds.responses[2].kind = ResponseKind::Embed;
+        kinds.responses[2].embed_title = "It's {1} time".to_string();
+        kinds.responses[2].embed_image = "https://example.com/kpop.png".to_string();
+        kinds.responses[3].kind = ResponseKind::File;
+        let index = RuleIndex::new(vec![kinds]);
+
+        let m = &index.matches("kpop time", None, 0, MatchPolicy::FirstMatch)[0];
+        let context = MessageContext::default();
+        let replies: Vec<Reply> = m
+            .rule
+            .responses
+            .iter()
+            .map(|r| m.render(r, &context))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                Reply::Inline("kpop".to_string()),
+                Reply::Reaction("🎵".to_string()),
+                Reply::Embed {
+                    title: "It's kpop time".to_string(),
+                    description: "kpop time".to_string(),
+                    image: "https://example.com/kpop.png".to_string(),
+                },
+                Reply::File("kpop.gif".to_string()),
+            ]
+        );
+    }
+
+    /// Compares the index with matching every pattern in a loop.
+    /// Run with `cargo test --release -- --ignored --nocapture rule_index_benchmark`.
+    #[test]
+    #[ignore]
+    fn rule_index_benchmark() {
+        use std::time::Instant;
+
+        let words = [
+            "kpop", "time", "week", "huh", "table", "flip", "cat", "thunder",
+        ];
+        let rules: Vec<Rule> = (0..500)
+            .map(|i| {
+                let pattern = format!("{} {}{}", words[i % words.len()], words[i % 7], i);
+                let mut rule = rule(i as i64, &[&pattern], &["response"]);
+                if i % 10 == 0 {
+                    rule.patterns[0].kind = PatternKind::WholeWord;
+                }
+                rule.settings.normalize = i % 3 == 0;
+                rule
+            })
+            .collect();
+        let messages: Vec<String> = (0..10_000)
+            .map(|i| {
+                format!(
+                    "Message number {i}: is it {} {}{} yet? What a week, huh",
+                    words[i % words.len()],
+                    words[i % 5],
+                    i % 1000
+                )
+            })
+            .collect();
+
+        let naive: Vec<(bool, Vec<Matcher>)> = rules
+            .iter()
+            .map(|rule| {
+                let matchers = rule
+                    .patterns
+                    .iter()
+                    .map(|p| Matcher::new(&p.pattern, p.kind, rule.settings.normalize).unwrap())
+                    .collect();
+                (rule.settings.normalize, matchers)
+            })
+            .collect();
+        let start = Instant::now();
+        let naive_matches = messages
+            .iter()
+            .filter(|message| {
+                let normalized = normalize(message);
+                naive.iter().any(|(normalize, matchers)| {
+                    let text = if *normalize { &normalized } else { *message };
+                    matchers.iter().any(|m| m.is_match(text))
+                })
+            })
+            .count();
+        let naive_elapsed = start.elapsed();
+
+        let index = RuleIndex::new(rules);
+        let start = Instant::now();
+        let index_matches = messages
+            .iter()
+            .filter(|message| !texts(&index, message, MatchPolicy::FirstMatch).is_empty())
+            .count();
+        let index_elapsed = start.elapsed();
+
+        println!("loop:  {naive_matches} matches in {naive_elapsed:?}");
+        println!("index: {index_matches} matches in {index_elapsed:?}");
+        assert_eq!(naive_matches, index_matches);
+    }
 }
@@ -0,0 +1,201 @@
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("a {{ is never closed")]
+    Unclosed,
+    #[error("a }} is never opened, write }}}} for a literal brace")]
+    Unopened,
+    #[error("unknown placeholder {{{0}}}")]
+    Unknown(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Author,
+    Channel,
+    Mention,
+    Match,
+    Group(usize),
+    Date,
+    Time,
+    Random(Vec<String>),
+}
+
+impl FromStr for Part {
+    type Err = TemplateError;
+
+    fn from_str(placeholder: &str) -> Result<Self, Self::Err> {
+        let part = match placeholder {
+            "author" => Part::Author,
+            "channel" => Part::Channel,
+            "mention" => Part::Mention,
+            "match" => Part::Match,
+            "date" => Part::Date,
+            "time" => Part::Time,
+            _ => {
+                if let Some(choices) = placeholder.strip_prefix("random:") {
+                    Part::Random(choices.split('|').map(String::from).collect())
+                } else if let Ok(group) = placeholder.parse() {
+                    Part::Group(group)
+                } else {
+                    return Err(TemplateError::Unknown(placeholder.to_string()));
+                }
+            }
+        };
+        Ok(part)
+    }
+}
+
+/// What a template can refer to about the message being answered.
+#[derive(Clone, Debug, Default)]
+pub struct MessageContext {
+    /// Display name of the author.
+    pub author: String,
+    pub author_id: u64,
+    pub channel_id: u64,
+    /// When the message was sent, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// A response with placeholders filled in when a rule answers:
+/// `{author}`, `{mention}`, `{channel}`, the matched text `{match}`, regex capture groups `{1}`,
+/// the `{date}` and `{time}` of the message, and one of several choices `{random:a|b|c}`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// A template that renders to `text` as is.
+    pub fn literal(text: &str) -> Self {
+        Template {
+            parts: vec![Part::Text(text.to_string())],
+        }
+    }
+
+    /// Fills in the placeholders. `captures` holds the whole match followed by its capture groups.
+    pub fn render(&self, context: &MessageContext, captures: &[&str]) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Author => rendered.push_str(&context.author),
+                Part::Channel => rendered.push_str(&format!("<#{}>", context.channel_id)),
+                Part::Mention => rendered.push_str(&format!("<@{}>", context.author_id)),
+                Part::Match => rendered.push_str(captures.first().unwrap_or(&"")),
+                Part::Group(i) => rendered.push_str(captures.get(*i).unwrap_or(&"")),
+                // Discord shows these in the local time zone of whoever reads them
+                Part::Date => rendered.push_str(&format!("<t:{}:D>", context.timestamp)),
+                Part::Time => rendered.push_str(&format!("<t:{}:t>", context.timestamp)),
+                Part::Random(choices) => {
+                    rendered.push_str(choices.choose(&mut thread_rng()).unwrap())
+                }
+            }
+        }
+        rendered
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.next_if_eq(&'{').is_some() => text.push('{'),
+                '}' if chars.next_if_eq(&'}').is_some() => text.push('}'),
+                '}' => return Err(TemplateError::Unopened),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(TemplateError::Unclosed),
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(placeholder.parse()?);
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> MessageContext {
+        MessageContext {
+            author: "Zoë".to_string(),
+            author_id: 1,
+            channel_id: 2,
+            timestamp: 1700000000,
+        }
+    }
+
+    fn render(template: &str, captures: &[&str]) -> String {
+        template
+            .parse::<Template>()
+            .unwrap()
+            .render(&context(), captures)
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        assert_eq!(
+            render(
+                "{mention} {author}, it's {match} in {channel}!",
+                &["kpop time"]
+            ),
+            "<@1> Zoë, it's kpop time in <#2>!"
+        );
+        assert_eq!(render("{2} before {1}", &["a b", "a", "b"]), "b before a");
+        assert_eq!(render("[{3}]", &["a b", "a", "b"]), "[]");
+        assert_eq!(
+            render("{date} {time}", &[]),
+            "<t:1700000000:D> <t:1700000000:t>"
+        );
+        assert_eq!(render("{{author}} }}{{", &[]), "{author} }{");
+        let choice = render("{random:a|b|c}", &[]);
+        assert!(["a", "b", "c"].contains(&choice.as_str()));
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert_eq!(
+            "Hi {author".parse::<Template>(),
+            Err(TemplateError::Unclosed)
+        );
+        assert_eq!(
+            "Hi author}".parse::<Template>(),
+            Err(TemplateError::Unopened)
+        );
+        assert_eq!(
+            "Hi {autor}".parse::<Template>(),
+            Err(TemplateError::Unknown("autor".to_string()))
+        );
+        assert_eq!(
+            "┬─┬ノ(º_ºノ)".parse::<Template>(),
+            Ok(Template::literal("┬─┬ノ(º_ºノ)"))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use serenity::model::channel::ReactionType;
+use url::Url;
+
+use crate::db::{Db, DbError, NewResponse, Pattern, PatternKind, ResponseKind, Rule, RuleSettings};
+use crate::message::{Matcher, RuleIndex};
+use crate::template::Template;
+
+/// The most a response can weigh. A shuffle bag gives a response as many times as its weight
+/// before repeating, so heavier responses would rarely let the others through anyway.
+pub const MAX_WEIGHT: u32 = 100;
+
+/// How far from 0 a priority may be, which leaves plenty of room to order rules.
+pub const MAX_PRIORITY: i64 = 1000;
+
+/// Checks the settings that don't depend on the patterns or responses, returning a message
+/// for each problem.
+pub fn validate_settings(settings: &RuleSettings) -> Vec<String> {
+    let mut errors = Vec::new();
+    if !(-MAX_PRIORITY..=MAX_PRIORITY).contains(&settings.priority) {
+        errors.push(format!(
+            "The priority must be between -{MAX_PRIORITY} and {MAX_PRIORITY}"
+        ));
+    }
+    errors
+}
+
+/// Compiles every pattern, returning a message for each one that is invalid.
+pub fn validate_patterns(
+    patterns: &[(String, PatternKind)],
+    settings: &RuleSettings,
+) -> Vec<String> {
+    patterns
+        .iter()
+        .filter_map(|(pattern, kind)| {
+            Matcher::new(pattern, *kind, settings.normalize)
+                .err()
+                .map(|why| format!("Invalid {kind} pattern {pattern:?}: {why}"))
+        })
+        .collect()
+}
+
+/// Checks that the patterns of a rule fit in with those of all other rules, as all of them
+/// share one size limit. `id` is `None` for a rule that isn't stored yet.
+pub async fn validate_pattern_sizes(
+    db: &Db,
+    id: Option<i64>,
+    patterns: &[(String, PatternKind)],
+    settings: &RuleSettings,
+) -> Result<Vec<String>, DbError> {
+    // Invalid patterns are reported on their own and never make it into the index
+    if !validate_patterns(patterns, settings).is_empty() {
+        return Ok(Vec::new());
+    }
+    // Stored rules have positive ids
+    let id = id.unwrap_or(0);
+    let mut rules = db.get_rules().await?;
+    rules.retain(|rule| rule.id != id);
+    rules.push(Rule {
+        id,
+        patterns: patterns
+            .iter()
+            .map(|(pattern, kind)| Pattern {
+                pattern: pattern.clone(),
+                kind: *kind,
+                ..Pattern::default()
+            })
+            .collect(),
+        settings: settings.clone(),
+        // Storing it makes it the most recently updated rule, the first to be left out
+        updated_at: i64::MAX,
+        ..Rule::default()
+    });
+    if RuleIndex::new(rules).left_out().contains(&id) {
+        return Ok(vec![
+            "The patterns are too big together with those of the other rules, \
+            use fewer or simpler regexes"
+                .to_string(),
+        ]);
+    }
+    Ok(Vec::new())
+}
+
+/// Checks the templates of every response and what each kind of response refers to, such as
+/// the files uploaded in the guild, returning a message for each problem.
+pub async fn validate_responses(
+    db: &Db,
+    guild_id: u64,
+    responses: &[NewResponse],
+) -> Result<Vec<String>, DbError> {
+    let files = db.get_files(guild_id).await?;
+    let mut errors = Vec::new();
+    for response in responses {
+        let text = &response.response;
+        if response.weight > MAX_WEIGHT {
+            errors.push(format!("The weight of {text:?} is more than {MAX_WEIGHT}"));
+        }
+        for template in [text, &response.embed_title] {
+            if let Err(why) = template.parse::<Template>() {
+                errors.push(format!("Invalid response {template:?}: {why}"));
+            }
+        }
+        match response.kind {
+            ResponseKind::Reaction if !is_emoji(text) => {
+                errors.push(format!("{text:?} is not an emoji"));
+            }
+            ResponseKind::Embed
+                if !response.embed_image.is_empty()
+                    && Url::parse(&response.embed_image).is_err() =>
+            {
+                errors.push(format!("{:?} is not a URL", response.embed_image));
+            }
+            ResponseKind::File if !files.iter().any(|f| &f.name == text) => {
+                errors.push(format!("There is no file called {text:?}, upload it first"));
+            }
+            _ => {}
+        }
+    }
+    Ok(errors)
+}
+
+/// Whether Discord can react with `text`. Serenity takes any text not starting with `<` for a
+/// Unicode emoji, so text with letters or only ASCII, like `hello` or `42`, is turned away here.
+fn is_emoji(text: &str) -> bool {
+    match ReactionType::try_from(text) {
+        Ok(ReactionType::Unicode(emoji)) => {
+            !emoji.is_ascii()
+                && !emoji
+                    .chars()
+                    .any(|c| c.is_alphabetic() || c.is_whitespace())
+        }
+        Ok(_) => !text.contains(char::is_whitespace),
+        Err(_) => false,
+    }
+}
+
+/// Parses channel ids separated by commas or whitespace.
+pub fn parse_channel_ids(text: &str) -> Result<Vec<u64>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|_| format!("{id:?} is not a channel id"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_must_be_emoji() {
+        for emoji in ["👍", "🇺🇸", "1️⃣", "<:thunder:123456789012345678>"] {
+            assert!(is_emoji(emoji), "{emoji}");
+        }
+        for text in ["hello", "42", "", "👍 ok", "ñ", "<:broken>"] {
+            assert!(!is_emoji(text), "{text}");
+        }
+    }
+}
@@ -2,20 +2,160 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use hypersynthetic::{html, HtmlFragment};
 use rocket::form::Form;
-use rocket::{delete, get, post, routes, Build, FromForm, Rocket, State};
+use rocket::fs::TempFile;
+use rocket::http::Status;
+use rocket::response::{self, Redirect, Responder};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{
+    catch, catchers, delete, get, post, put, routes, uri, Build, Either, FromForm, Request,
+    Response, Rocket, State,
+};
 
-use crate::components::RuleRow;
-use crate::db::Db;
+use crate::auth::{self, Editor};
+use crate::components::{
+    AddRuleButton, AuditTable, ErrorPage, FilesTable, PatternInput, ResponseInput, RuleEditor,
+    RuleHistory, RuleRow,
+};
+use crate::db::{Cooldowns, Db, DbError, NewResponse, PatternKind, Role, RuleScope, RuleSettings};
+use crate::validation::{
+    parse_channel_ids, validate_pattern_sizes, validate_patterns, validate_responses,
+    validate_settings,
+};
 
 #[derive(FromForm)]
-struct NewRuleForm {
+struct RuleForm {
     name: String,
     patterns: Vec<String>,
+    kinds: Vec<String>,
     responses: Vec<String>,
+    response_kinds: Vec<String>,
+    weights: Vec<String>,
+    embed_titles: Vec<String>,
+    embed_images: Vec<String>,
+    normalize: bool,
+    shuffle: bool,
+    #[field(default = "")]
+    priority: String,
+    stop_processing: bool,
+    #[field(default = "")]
+    global_cooldown: String,
+    #[field(default = "")]
+    channel_cooldown: String,
+    #[field(default = "")]
+    user_cooldown: String,
+    allowed_channels: String,
+    denied_channels: String,
+}
+
+impl RuleForm {
+    /// Non-empty patterns paired with the kind selected next to them.
+    fn patterns(&self) -> Vec<(String, PatternKind)> {
+        self.patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| !pattern.is_empty())
+            .map(|(i, pattern)| {
+                let kind = self.kinds.get(i).and_then(|k| k.parse().ok());
+                (pattern.clone(), kind.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// Non-empty responses with the kind, weight and embed fields entered next to them.
+    /// An empty weight counts as 1.
+    fn responses(&self) -> Vec<NewResponse> {
+        let field = |fields: &[String], i: usize| fields.get(i).cloned().unwrap_or_default();
+        self.responses
+            .iter()
+            .enumerate()
+            .filter(|(_, response)| !response.is_empty())
+            .map(|(i, response)| NewResponse {
+                response: response.clone(),
+                weight: self
+                    .weights
+                    .get(i)
+                    .and_then(|w| w.parse().ok())
+                    .unwrap_or(1),
+                kind: self
+                    .response_kinds
+                    .get(i)
+                    .and_then(|k| k.parse().ok())
+                    .unwrap_or_default(),
+                embed_title: field(&self.embed_titles, i),
+                embed_image: field(&self.embed_images, i),
+            })
+            .collect()
+    }
+
+    /// The settings of a rule of `guild_id`, where the editor's rules live.
+    fn settings(&self, guild_id: u64) -> RuleSettings {
+        RuleSettings {
+            normalize: self.normalize,
+            priority: self.priority.parse().unwrap_or_default(),
+            stop_processing: self.stop_processing,
+            cooldowns: Cooldowns {
+                global: self.global_cooldown.parse().unwrap_or_default(),
+                channel: self.channel_cooldown.parse().unwrap_or_default(),
+                user: self.user_cooldown.parse().unwrap_or_default(),
+            },
+            shuffle: self.shuffle,
+            scope: RuleScope {
+                guild_id: Some(guild_id),
+                allowed_channels: parse_channel_ids(&self.allowed_channels).unwrap_or_default(),
+                denied_channels: parse_channel_ids(&self.denied_channels).unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Checks the fields that are typed in as text, as the rule is built from whatever of
+    /// them parses.
+    fn validate_fields(&self) -> Vec<String> {
+        let mut errors: Vec<String> = self
+            .responses
+            .iter()
+            .zip(&self.weights)
+            .filter(|(response, weight)| !response.is_empty() && !weight.is_empty())
+            .filter(|(_, weight)| weight.parse::<u32>().is_err())
+            .map(|(response, weight)| {
+                format!("The weight {weight:?} of {response:?} is not a whole number")
+            })
+            .collect();
+        if !self.priority.is_empty() && self.priority.parse::<i64>().is_err() {
+            errors.push(format!(
+                "The priority {:?} is not a whole number",
+                self.priority
+            ));
+        }
+        let cooldowns = [
+            (&self.global_cooldown, "global"),
+            (&self.channel_cooldown, "channel"),
+            (&self.user_cooldown, "user"),
+        ];
+        for (cooldown, scope) in cooldowns {
+            if !cooldown.is_empty() && cooldown.parse::<u32>().is_err() {
+                errors.push(format!(
+                    "The {scope} cooldown {cooldown:?} is not a number of seconds"
+                ));
+            }
+        }
+        errors.extend(
+            [&self.allowed_channels, &self.denied_channels]
+                .into_iter()
+                .filter_map(|channels| parse_channel_ids(channels).err()),
+        );
+        errors
+    }
 }
 
 pub async fn create_web_server() -> Rocket<Build> {
-    let db = Db::new().await;
+    let db = Db::new()
+        .await
+        .unwrap_or_else(|why| panic!("Couldn't open the database: {why}"));
+    auth::sweep_expired_tokens(db.clone());
+    web_server(db)
+}
+
+fn web_server(db: Db) -> Rocket<Build> {
     rocket::build()
         .mount(
             "/",
@@ -24,17 +164,39 @@ pub async fn create_web_server() -> Rocket<Build> {
                 rules_table,
                 new_rule_form,
                 create_new_rule,
+                update_rule,
+                delete_rule,
+                lock_rule,
+                rule_history,
+                revert_rule,
+                delete_pattern,
+                delete_response,
                 additional_pattern_input,
                 additional_response_input,
                 deltete_whatever,
                 modify_rule_form,
+                files_table,
+                upload_file,
+                delete_file,
+                audit_log,
             ],
         )
+        .register(
+            "/",
+            catchers![unauthorized, forbidden, not_found, conflict, internal_error],
+        )
         .manage(db)
 }
 
+/// Lets htmx swap in the messages of the error catchers, which it skips by default
+/// because of their status.
+const SHOW_ERRORS: &str = "htmx:beforeSwap: if (event.detail.target.id === 'errors') {
+    event.detail.shouldSwap = true;
+    event.detail.isError = false;
+}";
+
 #[get("/")]
-fn home() -> HtmlFragment {
+fn home(_editor: Editor) -> HtmlFragment {
     html! {
         <!DOCTYPE html>
         <html lang="en">
@@ -48,141 +210,785 @@ fn home() -> HtmlFragment {
                 <link rel="stylesheet" href="https://unpkg.com/missing.css@1.0.9/dist/missing.min.css" />
             </head>
 
-            <body>
+            <body hx-on={ SHOW_ERRORS }>
+                <div id="errors"></div>
                 <div hx-get="/rules" hx-trigger="load"></div>
+                <div hx-get="/files" hx-trigger="load"></div>
+                <p><a href="/audit">"Audit log"</a></p>
             </body>
 
         </html>
     }
 }
 
-#[get("/rules")]
-async fn rules_table(db: &State<Db>) -> HtmlFragment {
-    let rules = db.get_rules().await;
+/// Lets handlers use `?` on database calls, answering with the catcher for the status.
+impl From<DbError> for Status {
+    fn from(error: DbError) -> Self {
+        match error {
+            DbError::NotFound => Status::NotFound,
+            DbError::Constraint(_) => Status::Conflict,
+            error => {
+                println!("Database error: {:?}", error);
+                Status::InternalServerError
+            }
+        }
+    }
+}
 
-    html! {
-        <table>
-            <caption>"Rules"</caption>
-            <thead>
-                <tr>
-                    <th>"name"</th>
-                    <th>"trigger"</th>
-                    <th>"responses"</th>
-                </tr>
-            </thead>
-            <tbody :for={rule in rules}>
-                <RuleRow rule={ &rule }/>
-            </tbody>
-            <tbody id="add-new-rule">
-                <tr>
-                    <td colspan="3">
-                        <button hx-get="/new-rule-form" hx-target="#add-new-rule" hx-swap="beforebegin">"Add +"</button>
-                    </td>
-                </tr>
-            </tbody>
-        </table>
+/// Makes sure a rule belongs to the guild of the editor, as if it didn't exist otherwise.
+async fn check_guild(db: &Db, editor: &Editor, rule_id: i64) -> Result<(), Status> {
+    if db.get_rule_guild(rule_id).await? == Some(editor.guild_id) {
+        Ok(())
+    } else {
+        Err(Status::NotFound)
     }
 }
 
-#[get("/new-rule-form")]
-async fn new_rule_form() -> HtmlFragment {
-    let id = match SystemTime::now().duration_since(UNIX_EPOCH) {
+fn check_role(editor: &Editor, role: Role) -> Result<(), Status> {
+    if editor.role >= role {
+        Ok(())
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+/// Makes sure the editor may change a rule of their guild, taking into account whether it is locked.
+async fn check_can_edit(db: &Db, editor: &Editor, rule_id: i64) -> Result<(), Status> {
+    check_guild(db, editor, rule_id).await?;
+    if editor.role.can_edit(&db.get_rule(rule_id).await?) {
+        Ok(())
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+/// The rules of the editor's guild, or only those that answer in the `channel` filtered on.
+#[get("/rules?<channel>")]
+async fn rules_table(
+    db: &State<Db>,
+    editor: Editor,
+    channel: Option<u64>,
+) -> Result<HtmlFragment, Status> {
+    let rules = db
+        .get_guild_rules(editor.guild_id)
+        .await?
+        .into_iter()
+        .filter(|rule| channel.is_none_or(|channel| rule.settings.scope.includes_channel(channel)));
+    let channel = channel.map(|id| id.to_string()).unwrap_or_default();
+
+    Ok(html! {
+        <div id="rules">
+            <form hx-get="/rules" hx-target="#rules" hx-swap="outerHTML" style="display: flex;">
+                <input name="channel" placeholder="channel id" value={ channel } />
+                <button>"Filter"</button>
+            </form>
+            <table>
+                <caption>"Rules"</caption>
+                <thead>
+                    <tr>
+                        <th>"name"</th>
+                        <th>"trigger"</th>
+                        <th>"responses"</th>
+                    </tr>
+                </thead>
+                <tbody :for={rule in rules}>
+                    <RuleRow rule={ &rule } role={ editor.role }/>
+                </tbody>
+                <AddRuleButton shown={ editor.role >= Role::Editor } />
+            </table>
+        </div>
+    })
+}
+
+fn new_form_id() -> String {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(n) => format!("id{}", n.as_millis()),
         _ => "rust_hasnt_been_invented_lol".to_string(),
-    };
+    }
+}
 
-    html! {
+#[get("/new-rule-form")]
+async fn new_rule_form(editor: Editor) -> Result<HtmlFragment, Status> {
+    check_role(&editor, Role::Editor)?;
+    let id = new_form_id();
+    let patterns = [(String::new(), PatternKind::default())];
+    let responses = [NewResponse::default()];
+
+    Ok(html! {
         <tbody>
-            <tr id={ id }>
-                <td>
-                    <input name="name" placeholder="name" />
-                </td>
-                <td>
-                    <input name="patterns" placeholder="pattern" />
-                    <button hx-get="/pattern-input" hx-swap="beforebegin">"Add another trigger"</button>
-                </td>
-                <td>
-                    <input name="responses" placeholder="response" />
-                    <button hx-get="/response-input" hx-swap="beforebegin">"Add another response"</button>
-                </td>
-            </tr>
-            <tr>
-                <td colspan="3">
-                    <button hx-post="/rules" hx-target="closest tbody" hx-include="#{id}">"Create"</button>
-                </td>
-            </tr>
+            <RuleEditor form_id={ &id } name="" patterns={ &patterns } responses={ &responses }
+                settings={ &RuleSettings::default() } rule_id={ None } errors={ &[] } />
         </tbody>
-    }
+    })
 }
 
 #[get("/modify-rule-form?<rule_id>")]
-async fn modify_rule_form(db: &State<Db>, rule_id: i64) -> HtmlFragment {
-    let rule = db.get_rule(rule_id).await;
+async fn modify_rule_form(
+    db: &State<Db>,
+    editor: Editor,
+    rule_id: i64,
+) -> Result<HtmlFragment, Status> {
+    check_can_edit(db, &editor, rule_id).await?;
+    let rule = db.get_rule(rule_id).await?;
+    let id = format!("rule-form-{}", rule.id);
+    let patterns: Vec<(String, PatternKind)> = rule
+        .patterns
+        .into_iter()
+        .map(|p| (p.pattern, p.kind))
+        .collect();
+    let responses: Vec<NewResponse> = rule.responses.into_iter().map(NewResponse::from).collect();
 
-    html! {
+    Ok(html! {
         <tbody>
-            <tr id="rule-form-{rule.id}">
-                <td>
-                    <input name="name" placeholder="name" value={ rule.name } />
-                </td>
-                <td>
-                    <input :for={pattern in rule.patterns} name="patterns" placeholder="pattern" value={ pattern } />
-                    <button hx-get="/pattern-input" hx-swap="beforebegin">"Add another trigger"</button>
-                </td>
-                <td>
-                    <input :for={response in rule.responses} name="responses" placeholder="response" value={ response } />
-                    <button hx-get="/response-input" hx-swap="beforebegin">"Add another response"</button>
-                </td>
-            </tr>
-            <tr>
-                <td colspan="3">
-                    <button hx-post="/rules" hx-target="closest tbody" hx-include="#rule-form-{rule.id}">"Save"</button>
-                </td>
-            </tr>
+            <RuleEditor form_id={ &id } name={ &rule.name } patterns={ &patterns }
+                responses={ &responses } settings={ &rule.settings } rule_id={ Some(rule.id) }
+                errors={ &[] } />
         </tbody>
-    }
+    })
 }
 
 #[post("/rules", data = "<form>")]
-async fn create_new_rule(db: &State<Db>, form: Form<NewRuleForm>) -> HtmlFragment {
+async fn create_new_rule(
+    db: &State<Db>,
+    editor: Editor,
+    form: Form<RuleForm>,
+) -> Result<HtmlFragment, Status> {
+    check_role(&editor, Role::Editor)?;
     let form = form.into_inner();
+    let patterns = form.patterns();
+    let responses = form.responses();
+    let settings = form.settings(editor.guild_id);
+
+    let mut errors = validate_patterns(&patterns, &settings);
+    errors.extend(validate_pattern_sizes(db, None, &patterns, &settings).await?);
+    errors.extend(validate_responses(db, editor.guild_id, &responses).await?);
+    errors.extend(validate_settings(&settings));
+    errors.extend(form.validate_fields());
+    if !errors.is_empty() {
+        return Ok(RuleEditor(
+            &new_form_id(),
+            &form.name,
+            &patterns,
+            &responses,
+            &settings,
+            None,
+            &errors,
+        ));
+    }
+
     let rule = db
         .create_rule(
             form.name,
-            form.patterns
-                .into_iter()
-                .filter(|p| !p.is_empty())
-                .collect(),
-            form.responses
-                .into_iter()
-                .filter(|r| !r.is_empty())
-                .collect(),
+            patterns,
+            responses,
+            settings,
+            &editor.user_id.to_string(),
         )
-        .await;
-    RuleRow(&rule)
+        .await?;
+    Ok(RuleRow(&rule, editor.role))
 }
 
-#[get("/pattern-input")]
-fn additional_pattern_input() -> HtmlFragment {
-    html! {
-        <div style="display: flex;">
-            <input name="patterns" placeholder="another pattern" />
-            <button hx-delete="/delete" hx-target="closest div" hx-swap="delete">"❌"</button>
-        </div>
+#[put("/rules/<id>", data = "<form>")]
+async fn update_rule(
+    db: &State<Db>,
+    editor: Editor,
+    id: i64,
+    form: Form<RuleForm>,
+) -> Result<HtmlFragment, Status> {
+    check_can_edit(db, &editor, id).await?;
+    let form = form.into_inner();
+    let patterns = form.patterns();
+    let responses = form.responses();
+    let settings = form.settings(editor.guild_id);
+
+    let mut errors = validate_patterns(&patterns, &settings);
+    errors.extend(validate_pattern_sizes(db, Some(id), &patterns, &settings).await?);
+    errors.extend(validate_responses(db, editor.guild_id, &responses).await?);
+    errors.extend(validate_settings(&settings));
+    errors.extend(form.validate_fields());
+    if !errors.is_empty() {
+        return Ok(RuleEditor(
+            &format!("rule-form-{id}"),
+            &form.name,
+            &patterns,
+            &responses,
+            &settings,
+            Some(id),
+            &errors,
+        ));
     }
+
+    let rule = db
+        .update_rule(
+            id,
+            form.name,
+            patterns,
+            responses,
+            settings,
+            &editor.user_id.to_string(),
+        )
+        .await?;
+    Ok(RuleRow(&rule, editor.role))
 }
 
-#[get("/response-input")]
-fn additional_response_input() -> HtmlFragment {
-    html! {
-        <div style="display: flex;">
-            <input name="responses" placeholder="another response" />
-            <button hx-delete="/delete" hx-target="closest div" hx-swap="delete">"❌"</button>
-        </div>
+#[delete("/rules/<id>")]
+async fn delete_rule(db: &State<Db>, editor: Editor, id: i64) -> Result<HtmlFragment, Status> {
+    check_can_edit(db, &editor, id).await?;
+    db.delete_rule(id, &editor.user_id.to_string()).await?;
+    Ok(html! {})
+}
+
+#[put("/rules/<id>/lock?<locked>")]
+async fn lock_rule(
+    db: &State<Db>,
+    editor: Editor,
+    id: i64,
+    locked: bool,
+) -> Result<HtmlFragment, Status> {
+    check_guild(db, &editor, id).await?;
+    check_role(&editor, Role::Admin)?;
+    db.set_rule_locked(id, locked, &editor.user_id.to_string())
+        .await?;
+    Ok(RuleRow(&db.get_rule(id).await?, editor.role))
+}
+
+#[delete("/rules/<rule_id>/patterns/<id>")]
+async fn delete_pattern(
+    db: &State<Db>,
+    editor: Editor,
+    rule_id: i64,
+    id: i64,
+) -> Result<HtmlFragment, Status> {
+    check_can_edit(db, &editor, rule_id).await?;
+    db.delete_pattern(rule_id, id, &editor.user_id.to_string())
+        .await?;
+    Ok(html! {})
+}
+
+#[delete("/rules/<rule_id>/responses/<id>")]
+async fn delete_response(
+    db: &State<Db>,
+    editor: Editor,
+    rule_id: i64,
+    id: i64,
+) -> Result<HtmlFragment, Status> {
+    check_can_edit(db, &editor, rule_id).await?;
+    db.delete_response(rule_id, id, &editor.user_id.to_string())
+        .await?;
+    Ok(html! {})
+}
+
+#[derive(FromForm)]
+struct FileUpload<'r> {
+    file: TempFile<'r>,
+}
+
+#[get("/files")]
+async fn files_table(db: &State<Db>, editor: Editor) -> Result<HtmlFragment, Status> {
+    Ok(FilesTable(
+        &db.get_files(editor.guild_id).await?,
+        &[],
+        editor.role >= Role::Editor,
+    ))
+}
+
+#[post("/files", data = "<form>")]
+async fn upload_file(
+    db: &State<Db>,
+    editor: Editor,
+    form: Form<FileUpload<'_>>,
+) -> Result<HtmlFragment, Status> {
+    check_role(&editor, Role::Editor)?;
+    let file = &form.file;
+    let name = match (file.name(), file.content_type().and_then(|t| t.extension())) {
+        (Some(name), Some(extension)) => format!("{name}.{extension}"),
+        (Some(name), None) => name.to_string(),
+        (None, _) => {
+            let errors = ["Choose a file to upload".to_string()];
+            return Ok(FilesTable(
+                &db.get_files(editor.guild_id).await?,
+                &errors,
+                true,
+            ));
+        }
+    };
+
+    let mut data = Vec::new();
+    let read = match file.open().await {
+        Ok(reader) => {
+            rocket::tokio::pin!(reader);
+            reader.read_to_end(&mut data).await
+        }
+        Err(why) => Err(why),
+    };
+    if let Err(why) = read {
+        let errors = [format!("Couldn't read {name}: {why}")];
+        return Ok(FilesTable(
+            &db.get_files(editor.guild_id).await?,
+            &errors,
+            true,
+        ));
     }
+
+    db.create_file(editor.guild_id, &name, &data, &editor.user_id.to_string())
+        .await?;
+    Ok(FilesTable(&db.get_files(editor.guild_id).await?, &[], true))
+}
+
+#[delete("/files/<id>")]
+async fn delete_file(db: &State<Db>, editor: Editor, id: i64) -> Result<HtmlFragment, Status> {
+    check_role(&editor, Role::Editor)?;
+    db.delete_file(editor.guild_id, id).await?;
+    Ok(html! {})
+}
+
+#[get("/rules/<id>/history")]
+async fn rule_history(db: &State<Db>, editor: Editor, id: i64) -> Result<HtmlFragment, Status> {
+    history_page(db, &editor, id, &[]).await
+}
+
+/// The history of a rule of the editor's guild, with the `errors` that kept it from being
+/// reverted. Deleted rules keep their history, but only rules that still exist can be reverted.
+async fn history_page(
+    db: &Db,
+    editor: &Editor,
+    id: i64,
+    errors: &[String],
+) -> Result<HtmlFragment, Status> {
+    let history = db.get_rule_history(editor.guild_id, id).await?;
+    let name = history
+        .first()
+        .and_then(|change| change.after.as_ref().or(change.before.as_ref()))
+        .map(|rule| rule.name.as_str())
+        .ok_or(Status::NotFound)?;
+    let title = format!("History of rule #{id} {name}");
+    let can_revert = match db.get_rule(id).await {
+        Ok(rule) => {
+            rule.settings.scope.guild_id == Some(editor.guild_id) && editor.role.can_edit(&rule)
+        }
+        Err(DbError::NotFound) => false,
+        Err(why) => return Err(why.into()),
+    };
+
+    Ok(html! {
+        <!DOCTYPE html>
+        <html lang="en">
+
+            <head>
+                <title>{ "Slackbot rule history" }</title>
+                <meta charset="utf-8" />
+                <link rel="stylesheet" href="https://unpkg.com/missing.css@1.0.9/dist/missing.min.css" />
+            </head>
+
+            <body>
+                <h1>{ title }</h1>
+                <p><a href="/">"Back to the rules"</a></p>
+                <p :for={error in errors} class="bad color">{ error }</p>
+                <RuleHistory history={ &history } can_revert={ can_revert } />
+            </body>
+
+        </html>
+    })
+}
+
+/// Reverts a rule to one of its revisions, unless the revision no longer passes validation,
+/// for instance because a file it sends was deleted since.
+#[post("/rules/<id>/revert/<change_id>")]
+async fn revert_rule(
+    db: &State<Db>,
+    editor: Editor,
+    id: i64,
+    change_id: i64,
+) -> Result<Either<HtmlFragment, Redirect>, Status> {
+    check_can_edit(db, &editor, id).await?;
+    let revision = db.get_revision(id, change_id).await?;
+    let patterns: Vec<_> = revision
+        .patterns
+        .iter()
+        .map(|p| (p.pattern.clone(), p.kind))
+        .collect();
+    let responses: Vec<_> = revision
+        .responses
+        .iter()
+        .cloned()
+        .map(NewResponse::from)
+        .collect();
+    let mut errors = validate_patterns(&patterns, &revision.settings);
+    errors.extend(validate_pattern_sizes(db, Some(id), &patterns, &revision.settings).await?);
+    errors.extend(validate_responses(db, editor.guild_id, &responses).await?);
+    errors.extend(validate_settings(&revision.settings));
+    if !errors.is_empty() {
+        let page = history_page(db, &editor, id, &errors).await?;
+        return Ok(Either::Left(page));
+    }
+
+    db.revert_rule(id, revision, &editor.user_id.to_string())
+        .await?;
+    Ok(Either::Right(Redirect::to(uri!(rule_history(id)))))
+}
+
+/// Every change to the rules of the editor's guild, or only those to one `rule` or by one `user`.
+#[get("/audit?<rule>&<user>")]
+async fn audit_log(
+    db: &State<Db>,
+    editor: Editor,
+    rule: Option<i64>,
+    user: Option<&str>,
+) -> Result<HtmlFragment, Status> {
+    let user = user.filter(|user| !user.is_empty());
+    let changes = db.get_rule_changes(editor.guild_id, rule, user).await?;
+    let rule = rule.map(|id| id.to_string()).unwrap_or_default();
+    let user = user.unwrap_or_default();
+
+    Ok(html! {
+        <!DOCTYPE html>
+        <html lang="en">
+
+            <head>
+                <title>{ "Slackbot audit log" }</title>
+                <meta charset="utf-8" />
+                <link rel="stylesheet" href="https://unpkg.com/missing.css@1.0.9/dist/missing.min.css" />
+            </head>
+
+            <body>
+                <h1>"Audit log"</h1>
+                <p><a href="/">"Back to the rules"</a></p>
+                <form action="/audit" method="get" style="display: flex;">
+                    <input name="rule" placeholder="rule id" value={ rule } />
+                    <input name="user" placeholder="user id" value={ user } />
+                    <button>"Filter"</button>
+                </form>
+                <AuditTable changes={ &changes } />
+            </body>
+
+        </html>
+    })
+}
+
+#[get("/pattern-input")]
+fn additional_pattern_input(_editor: Editor) -> HtmlFragment {
+    PatternInput("", PatternKind::default())
+}
+
+#[get("/response-input")]
+fn additional_response_input(_editor: Editor) -> HtmlFragment {
+    ResponseInput(&NewResponse::default())
 }
 
 #[delete("/delete")]
-fn deltete_whatever() -> HtmlFragment {
+fn deltete_whatever(_editor: Editor) -> HtmlFragment {
     html! {}
 }
+
+#[catch(401)]
+fn unauthorized() -> HtmlFragment {
+    html! {
+        <!DOCTYPE html>
+        <html lang="en">
+
+            <head>
+                <title>{ "Slackbot" }</title>
+                <meta charset="utf-8" />
+                <link rel="stylesheet" href="https://unpkg.com/missing.css@1.0.9/dist/missing.min.css" />
+            </head>
+
+            <body>
+                <h1>"This link has expired"</h1>
+                <p>"Type !edit in Discord to get a fresh one."</p>
+            </body>
+
+        </html>
+    }
+}
+
+/// An error shown in the box above the rules when htmx made the request, whichever element
+/// made it, or as a page of its own otherwise, such as for links to the history of a rule.
+struct ErrorMessage(&'static str);
+
+impl<'r> Responder<'r, 'static> for ErrorMessage {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let ErrorMessage(message) = self;
+        if !request.headers().contains("HX-Request") {
+            return ErrorPage(message).respond_to(request);
+        }
+        let fragment = html! { <p class="bad color">{ message }</p> };
+        Response::build_from(fragment.respond_to(request)?)
+            .raw_header("HX-Retarget", "#errors")
+            .raw_header("HX-Reswap", "innerHTML")
+            .ok()
+    }
+}
+
+#[catch(403)]
+fn forbidden() -> ErrorMessage {
+    ErrorMessage("Your role doesn't allow that. Only admins can change locked rules.")
+}
+
+#[catch(404)]
+fn not_found() -> ErrorMessage {
+    ErrorMessage("That doesn't exist, it may have been deleted in the meantime.")
+}
+
+#[catch(409)]
+fn conflict() -> ErrorMessage {
+    ErrorMessage("That doesn't fit with what is stored, reload and try again.")
+}
+
+#[catch(500)]
+fn internal_error() -> ErrorMessage {
+    ErrorMessage("Something went wrong, try again later.")
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Cookie, Header};
+    use rocket::local::asynchronous::{Client, LocalRequest};
+
+    use super::*;
+    use crate::db::ResponseKind;
+
+    const GUILD: u64 = 1;
+
+    /// A web server on a fresh database, with a rule in `GUILD` and a session there for
+    /// each role, named after it.
+    async fn client() -> (Client, Db, i64) {
+        // Each test gets its own database, shared by the connections of the pool
+        let url = format!(
+            "sqlite:file:{}?mode=memory&cache=shared",
+            uuid::Uuid::new_v4()
+        );
+        let db = Db::connect(&url).await.unwrap();
+        let mut settings = RuleSettings::default();
+        settings.scope.guild_id = Some(GUILD);
+        let rule = db
+            .create_rule(
+                "kpop".to_string(),
+                vec![("kpop".to_string(), PatternKind::Substring)],
+                Vec::new(),
+                settings,
+                "1",
+            )
+            .await
+            .unwrap();
+        for (user, role) in [("2", Role::Viewer), ("3", Role::Editor), ("4", Role::Admin)] {
+            db.create_session(role.as_str(), user, GUILD, role)
+                .await
+                .unwrap();
+        }
+        let client = Client::untracked(web_server(db.clone())).await.unwrap();
+        (client, db, rule.id)
+    }
+
+    async fn status(request: LocalRequest<'_>, role: Role) -> Status {
+        request
+            .cookie(Cookie::new("session", role.as_str()))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn viewers_cannot_change_rules() {
+        let (client, db, id) = client().await;
+        let lock = format!("/rules/{id}/lock?locked=true");
+        assert_eq!(
+            status(client.put(lock), Role::Viewer).await,
+            Status::Forbidden
+        );
+        let delete = format!("/rules/{id}");
+        assert_eq!(
+            status(client.delete(delete), Role::Viewer).await,
+            Status::Forbidden
+        );
+        assert_eq!(status(client.get("/rules"), Role::Viewer).await, Status::Ok);
+        assert!(db.get_rule(id).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn only_admins_change_locked_rules() {
+        let (client, db, id) = client().await;
+        db.set_rule_locked(id, true, "4").await.unwrap();
+        let delete = format!("/rules/{id}");
+        assert_eq!(
+            status(client.delete(&delete), Role::Editor).await,
+            Status::Forbidden
+        );
+        assert!(db.get_rule(id).await.is_ok());
+        assert_eq!(
+            status(client.delete(&delete), Role::Admin).await,
+            Status::Ok
+        );
+        assert!(db.get_rule(id).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn errors_are_pages_unless_htmx_asks() {
+        let (client, _, _) = client().await;
+        let cookie = || Cookie::new("session", Role::Viewer.as_str());
+        let page = client
+            .get("/rules/999/history")
+            .cookie(cookie())
+            .dispatch()
+            .await;
+        assert_eq!(page.status(), Status::NotFound);
+        assert!(page.headers().get_one("HX-Retarget").is_none());
+        assert!(page
+            .into_string()
+            .await
+            .unwrap()
+            .contains("Back to the rules"));
+
+        let fragment = client
+            .get("/rules/999/history")
+            .cookie(cookie())
+            .header(Header::new("HX-Request", "true"))
+            .dispatch()
+            .await;
+        assert_eq!(fragment.status(), Status::NotFound);
+        assert_eq!(fragment.headers().get_one("HX-Retarget"), Some("#errors"));
+    }
+
+    #[rocket::async_test]
+    async fn revisions_are_validated_before_reverting() {
+        let (client, db, id) = client().await;
+        let rule = db.get_rule(id).await.unwrap();
+        let patterns = vec![("kpop".to_string(), PatternKind::Substring)];
+        let file = NewResponse {
+            response: "kpop.png".to_string(),
+            kind: ResponseKind::File,
+            ..NewResponse::default()
+        };
+        db.create_file(GUILD, "kpop.png", b"png", "4")
+            .await
+            .unwrap();
+        let update = |responses| {
+            let (name, patterns) = (rule.name.clone(), patterns.clone());
+            db.update_rule(id, name, patterns, responses, rule.settings.clone(), "4")
+        };
+        update(vec![file]).await.unwrap();
+        update(Vec::new()).await.unwrap();
+        let file_id = db.get_files(GUILD).await.unwrap()[0].id;
+        db.delete_file(GUILD, file_id).await.unwrap();
+
+        let with_file = db.get_rule_history(GUILD, id).await.unwrap()[1].id;
+        let revert = client
+            .post(format!("/rules/{id}/revert/{with_file}"))
+            .cookie(Cookie::new("session", Role::Admin.as_str()))
+            .dispatch()
+            .await;
+        assert_eq!(revert.status(), Status::Ok);
+        assert!(revert
+            .into_string()
+            .await
+            .unwrap()
+            .contains("There is no file called"));
+        assert!(db.get_rule(id).await.unwrap().responses.is_empty());
+
+        // The history outlives the rule
+        db.delete_rule(id, "4").await.unwrap();
+        let history = format!("/rules/{id}/history");
+        assert_eq!(status(client.get(history), Role::Viewer).await, Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn new_links_replace_the_session() {
+        let (client, db, _) = client().await;
+        let mut settings = RuleSettings::default();
+        settings.scope.guild_id = Some(GUILD + 1);
+        let other = db
+            .create_rule(
+                "other guild".to_string(),
+                Vec::new(),
+                Vec::new(),
+                settings,
+                "1",
+            )
+            .await
+            .unwrap();
+        db.create_token("link", "2", GUILD + 1, Role::Editor)
+            .await
+            .unwrap();
+
+        let response = client
+            .get("/rules?token=link")
+            .cookie(Cookie::new("session", Role::Viewer.as_str()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let cookie = response.cookies().get("session").unwrap();
+        assert_eq!(cookie.http_only(), Some(true));
+        let session = cookie.value().to_string();
+        assert_ne!(session, Role::Viewer.as_str());
+        assert!(response
+            .into_string()
+            .await
+            .unwrap()
+            .contains("other guild"));
+
+        let delete = client
+            .delete(format!("/rules/{}", other.id))
+            .cookie(Cookie::new("session", session))
+            .dispatch()
+            .await;
+        assert_eq!(delete.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn rules_too_big_for_the_index_are_refused() {
+        let (client, db, _) = client().await;
+        let big = vec![(r"\w{60}".to_string(), PatternKind::Regex); 3];
+        let count = db.get_rules().await.unwrap().len() + 1;
+        let mut settings = RuleSettings::default();
+        settings.scope.guild_id = Some(GUILD + 1);
+        db.create_rule("big".to_string(), big, Vec::new(), settings, "1")
+            .await
+            .unwrap();
+
+        let form = "name=big&patterns=\\w{60}&kinds=regex&patterns=\\w{60}&kinds=regex\
+            &patterns=\\w{60}&kinds=regex&allowed_channels=&denied_channels=";
+        let response = client
+            .post("/rules")
+            .header(ContentType::Form)
+            .body(form)
+            .cookie(Cookie::new("session", Role::Editor.as_str()))
+            .dispatch()
+            .await;
+        assert!(response.into_string().await.unwrap().contains("too big"));
+        assert_eq!(db.get_rules().await.unwrap().len(), count);
+    }
+
+    #[rocket::async_test]
+    async fn fields_that_dont_parse_are_reported() {
+        let (client, db, _) = client().await;
+        let count = db.get_rules().await.unwrap().len();
+        let form = "name=weights&patterns=hi&kinds=substring&responses=a&response_kinds=text\
+            &weights=-1&responses=b&response_kinds=text&weights=1e3&priority=high\
+            &global_cooldown=&channel_cooldown=-5&user_cooldown=10\
+            &allowed_channels=&denied_channels=";
+        let response = client
+            .post("/rules")
+            .header(ContentType::Form)
+            .body(form)
+            .cookie(Cookie::new("session", Role::Editor.as_str()))
+            .dispatch()
+            .await;
+        let page = response.into_string().await.unwrap();
+        assert!(page.contains(r#"weight "-1" of "a" is not a whole number"#));
+        assert!(page.contains(r#"weight "1e3" of "b" is not a whole number"#));
+        assert!(page.contains(r#"priority "high" is not a whole number"#));
+        assert!(page.contains(r#"channel cooldown "-5" is not a number of seconds"#));
+        assert!(!page.contains("user cooldown"));
+        assert_eq!(db.get_rules().await.unwrap().len(), count);
+    }
+
+    #[rocket::async_test]
+    async fn taking_access_away_ends_sessions() {
+        let (client, db, _) = client().await;
+        db.set_user_access(GUILD, 3, None).await.unwrap();
+        assert_eq!(
+            status(client.get("/rules"), Role::Editor).await,
+            Status::Unauthorized
+        );
+        assert_eq!(status(client.get("/rules"), Role::Viewer).await, Status::Ok);
+    }
+}